@@ -1,4 +1,4 @@
-use crate::util;
+use crate::{encryption, util, wildcard};
 use tame_gcs::http;
 
 #[derive(clap::Parser, Debug)]
@@ -19,36 +19,41 @@ through the end of the object, and -numbytes means to return the
 last numbytes of the object."
     )]
     range: Option<String>,
-    /// The gs:// url to the object
-    url: url::Url,
+    /// The gs:// url to the object, which may contain wildcards to
+    /// concatenate every object that matches
+    url: String,
 }
 
 pub async fn cmd(ctx: &util::RequestContext, args: Args) -> anyhow::Result<()> {
-    let oid = util::gs_url_to_object_id(&args.url)?;
-
-    let mut download_req = ctx.obj.download(
-        &(
-            oid.bucket(),
-            oid.object()
-                .ok_or_else(|| anyhow::anyhow!("invalid object name specified"))?,
-        ),
-        None,
-    )?;
-
-    if let Some(range) = args.range {
-        // The format specified in the arguments exactly matches those HTTP range
-        // value, so we just pass it along (almost) verbatim and let GCS actually
-        // handle nefarious users for us
-        download_req.headers_mut().insert(
-            http::header::RANGE,
-            http::header::HeaderValue::from_str(&format!("bytes={range}"))?,
-        );
+    let pattern = wildcard::GsPattern::parse(&args.url)?;
+
+    for oid in pattern.expand(ctx).await? {
+        let mut download_req = ctx.obj.download(
+            &(
+                oid.bucket(),
+                oid.object()
+                    .ok_or_else(|| anyhow::anyhow!("invalid object name specified"))?,
+            ),
+            None,
+        )?;
+
+        if let Some(range) = &args.range {
+            // The format specified in the arguments exactly matches those HTTP range
+            // value, so we just pass it along (almost) verbatim and let GCS actually
+            // handle nefarious users for us
+            download_req.headers_mut().insert(
+                http::header::RANGE,
+                http::header::HeaderValue::from_str(&format!("bytes={range}"))?,
+            );
+        }
+
+        let key = encryption::key_for(ctx, &oid, None).await?;
+        encryption::add_headers(download_req.headers_mut(), key.as_ref())?;
+
+        let response = util::execute_streaming(ctx, download_req).await?;
+
+        response.copy_to(&mut std::io::stdout().lock()).await?;
     }
 
-    let mut response: tame_gcs::objects::DownloadObjectResponse =
-        util::execute(ctx, download_req).await?;
-
-    std::io::copy(&mut response, &mut std::io::stdout())?;
-
     Ok(())
 }
@@ -54,6 +54,13 @@ impl ColorCtx {
         }
     }
 
+    /// Whether stderr is a terminal, which output that is redrawn in place,
+    /// eg. progress bars, requires
+    #[inline]
+    pub fn stderr_is_terminal(&self) -> bool {
+        self.stderr_isatty
+    }
+
     /// Paints a string destined for stdout.
     ///
     /// Analogous to `p.paint(input)`, but produces a plain string if color should not be used for
@@ -0,0 +1,119 @@
+//! Composition of objects, which concatenates existing objects in the same
+//! bucket into a new object without any of the data passing through the
+//! client. See <https://cloud.google.com/storage/docs/json_api/v1/objects/compose>
+
+use crate::{encryption, util};
+use anyhow::Context as _;
+use tame_gcs::{
+    http,
+    objects::{self, Metadata},
+};
+
+/// The maximum number of source objects in a single compose request
+pub const MAX_SOURCES: usize = 32;
+
+/// A specific generation of an object to compose
+pub struct Source {
+    pub name: String,
+    pub generation: i64,
+}
+
+/// The optional parameters for a compose request
+#[derive(Default)]
+pub struct ComposeOptional<'a> {
+    /// The predefined ACL to apply to the destination, as named in the API
+    pub destination_predefined_acl: Option<&'a str>,
+    /// The Cloud KMS key used to encrypt the destination
+    pub kms_key_name: Option<&'a str>,
+    /// Preconditions on the destination object
+    pub conditionals: tame_gcs::common::Conditionals,
+    /// Metadata for the destination that tame-gcs' `Metadata` can't hold
+    pub extra_metadata: Option<&'a util::ExtraMetadata>,
+}
+
+/// Concatenates the sources, in order, into the destination object, which
+/// is created with the specified metadata
+pub async fn compose(
+    ctx: &util::RequestContext,
+    dst: &util::GsUrl,
+    sources: &[Source],
+    metadata: &Metadata,
+    optional: ComposeOptional<'_>,
+) -> anyhow::Result<Metadata> {
+    anyhow::ensure!(
+        (1..=MAX_SOURCES).contains(&sources.len()),
+        "can only compose between 1 and {MAX_SOURCES} objects, not {}",
+        sources.len()
+    );
+
+    // tame-gcs doesn't support compose, but its URL is just the object's URL
+    // with /compose appended, so take that from a get request, which also
+    // takes care of encoding the object name
+    let get_req = ctx.obj.get(
+        &(
+            dst.bucket(),
+            dst.object()
+                .context("must provide a full object name to compose into")?,
+        ),
+        Some(objects::GetObjectOptional {
+            standard_params: tame_gcs::common::StandardQueryParameters {
+                fields: Some(util::OBJECT_FIELDS),
+                ..Default::default()
+            },
+            ..Default::default()
+        }),
+    )?;
+
+    let (mut parts, _) = get_req.into_parts();
+
+    let uri = parts.uri.to_string();
+    let (path, query) = uri.split_once('?').unwrap_or((&uri, ""));
+
+    let mut query = url::form_urlencoded::Serializer::new(query.to_owned());
+    if let Some(acl) = optional.destination_predefined_acl {
+        query.append_pair("destinationPredefinedAcl", acl);
+    }
+    if let Some(kms_key) = optional.kms_key_name {
+        query.append_pair("kmsKeyName", kms_key);
+    }
+    let conditionals = serde_urlencoded::to_string(&optional.conditionals)?;
+    if !conditionals.is_empty() {
+        query.extend_pairs(url::form_urlencoded::parse(conditionals.as_bytes()));
+    }
+
+    parts.uri = format!("{path}/compose?{}", query.finish()).parse()?;
+    parts.method = http::Method::POST;
+
+    let destination = match optional.extra_metadata {
+        Some(extra) => extra.merge(metadata)?,
+        None => serde_json::to_value(metadata)?,
+    };
+
+    let body = serde_json::to_vec(&serde_json::json!({
+        "sourceObjects": sources.iter().map(|src| serde_json::json!({
+            "name": src.name,
+            "generation": src.generation,
+            // Ensure a source isn't replaced by someone else while we compose
+            "objectPreconditions": { "ifGenerationMatch": src.generation },
+        })).collect::<Vec<_>>(),
+        "destination": destination,
+    }))?;
+
+    parts.headers.insert(
+        http::header::CONTENT_TYPE,
+        http::HeaderValue::from_static("application/json; charset=UTF-8"),
+    );
+    parts
+        .headers
+        .insert(http::header::CONTENT_LENGTH, body.len().into());
+
+    // The sources have to be encrypted with the same customer-supplied key as
+    // the destination, if any
+    encryption::add_headers(&mut parts.headers, ctx.encryption_keys.encryption())?;
+
+    let compose_req = http::Request::from_parts(parts, std::io::Cursor::new(body));
+
+    // The response is the destination's object resource, exactly like get
+    let compose_res: objects::GetObjectResponse = util::execute(ctx, compose_req).await?;
+    Ok(compose_res.metadata)
+}
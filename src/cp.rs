@@ -1,8 +1,23 @@
-use crate::util;
+use crate::{
+    color::ColorCtx, compose, encryption, hash, mime, posix, progress, resumable, util, walk,
+    wildcard,
+};
 use anyhow::Context as _;
-use std::{convert::TryFrom, path::PathBuf};
+use std::{
+    convert::TryFrom,
+    fs,
+    path::{Path, PathBuf},
+};
 use tame_gcs::objects::{self, Metadata};
 
+/// The object metadata fields that can be set by a request, as opposed to
+/// the fields GCS fills in
+const WRITABLE_METADATA_FIELDS: &str = "cacheControl, contentDisposition, contentEncoding, contentLanguage, contentType, customTime, metadata";
+
+/// The prefix of the temporary objects that the parts of a parallel composite
+/// upload are uploaded to
+const COMPOSITE_PART_PREFIX: &str = "gsutil-tmp/composite/";
+
 #[derive(clap::ValueEnum, Clone, Copy)]
 enum Acl {
     ProjectPrivate,
@@ -13,6 +28,20 @@ enum Acl {
     BucketOwnerFullControl,
 }
 
+impl Acl {
+    /// The name of the ACL in the API
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::ProjectPrivate => "projectPrivate",
+            Self::Private => "private",
+            Self::PublicRead => "publicRead",
+            Self::AuthenticatedRead => "authenticatedRead",
+            Self::BucketOwnerRead => "bucketOwnerRead",
+            Self::BucketOwnerFullControl => "bucketOwnerFullControl",
+        }
+    }
+}
+
 impl From<Acl> for tame_gcs::common::PredefinedAcl {
     fn from(a: Acl) -> Self {
         match a {
@@ -26,29 +55,339 @@ impl From<Acl> for tame_gcs::common::PredefinedAcl {
     }
 }
 
-#[derive(clap::Parser)]
-pub struct Args {
+/// The storage classes that can be selected for a destination object, which
+/// are passed to the API as plain strings, see [`util::OBJECT_FIELDS`]
+#[derive(clap::ValueEnum, Clone, Copy)]
+enum StorageClass {
+    Standard,
+    Nearline,
+    Coldline,
+    Archive,
+}
+
+impl StorageClass {
+    /// The name of the storage class in the API
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Standard => "STANDARD",
+            Self::Nearline => "NEARLINE",
+            Self::Coldline => "COLDLINE",
+            Self::Archive => "ARCHIVE",
+        }
+    }
+}
+
+/// Object metadata that can be set on uploaded objects with `-h`, named after
+/// the equivalent HTTP headers the same as gsutil
+#[derive(Clone)]
+enum MetadataHeader {
+    CacheControl(String),
+    ContentDisposition(String),
+    ContentEncoding(String),
+    ContentLanguage(String),
+    ContentType(String),
+    CustomTime(String),
+    /// An `x-goog-meta-<key>` header, which sets the key in the custom metadata
+    Custom(String, String),
+}
+
+impl std::str::FromStr for MetadataHeader {
+    type Err = clap::Error;
+
+    fn from_str(src: &str) -> Result<Self, Self::Err> {
+        let invalid = |msg: String| clap::Error::raw(clap::error::ErrorKind::ValueValidation, msg);
+
+        let (name, value) = src
+            .split_once(':')
+            .ok_or_else(|| invalid(format!("'{src}' is not of the form 'Header:Value'")))?;
+        let (name, value) = (name.trim(), value.trim().to_owned());
+
+        Ok(match name.to_ascii_lowercase().as_str() {
+            "cache-control" => Self::CacheControl(value),
+            "content-disposition" => Self::ContentDisposition(value),
+            "content-encoding" => Self::ContentEncoding(value),
+            "content-language" => Self::ContentLanguage(value),
+            "content-type" => Self::ContentType(value),
+            "custom-time" => Self::CustomTime(value),
+            lower => match lower.strip_prefix("x-goog-meta-") {
+                // Custom metadata keys keep their case
+                Some(key) if !key.is_empty() => {
+                    Self::Custom(name["x-goog-meta-".len()..].to_owned(), value)
+                }
+                _ => {
+                    return Err(invalid(format!(
+                        "unsupported header '{name}', only Cache-Control, Content-Disposition, Content-Encoding, Content-Language, Content-Type, Custom-Time, and x-goog-meta-<key> can be set"
+                    )))
+                }
+            },
+        })
+    }
+}
+
+/// The options that control how each individual file or object is copied,
+/// which are shared by every command that copies data
+#[derive(clap::Args)]
+#[command(disable_help_flag = true)]
+pub struct Options {
+    /// Print help, -h sets metadata instead
+    #[clap(long, action = clap::ArgAction::Help)]
+    help: Option<bool>,
     /// Predefined ACL to apply to the destination GCS object
     #[clap(short = 'a')]
     predef_acl: Option<Acl>,
-    /// A gs: URL or filepath for the source path to copy from,
-    /// wildcards are not currently supported
-    src_url: String,
-    /// A gs: URL or filepath for the destination to copy to,
-    /// wildcards are not currently supported
+    /// Always upload files with a resumable upload, regardless of their size
+    #[clap(long)]
+    resumable: bool,
+    /// Files at least this large are uploaded with a resumable upload, which
+    /// sends the file in chunks so that an interrupted upload doesn't have to
+    /// start over. Running the same command again after an interruption will
+    /// resume the upload where it stopped, unless the file or any of the
+    /// upload's options have changed. Uploads are tracked in
+    /// `~/.gsutil/tracker-files`.
+    #[clap(long, default_value = "8M")]
+    resumable_threshold: util::ByteSize,
+    /// The storage class for the destination object, defaults to the default
+    /// storage class of the destination bucket
+    #[clap(short = 's', long)]
+    storage_class: Option<StorageClass>,
+    /// The Cloud KMS key used to encrypt the destination object, defaults to
+    /// the default key of the destination bucket, if any, eg.
+    /// `projects/<project>/locations/<location>/keyRings/<ring>/cryptoKeys/<key>`
+    #[clap(long)]
+    kms_key: Option<String>,
+    /// Calculate and verify md5 hashes in addition to crc32c hashes. Note
+    /// that composite objects don't have an md5 hash.
+    #[clap(long)]
+    md5: bool,
+    /// Objects at least this large are downloaded in slices, each of which is
+    /// fetched concurrently with its own byte range request and written
+    /// directly to its offset in the destination file
+    #[clap(long, default_value = "150M")]
+    sliced_threshold: util::ByteSize,
+    /// The number of slices that large objects are downloaded in, 1 disables
+    /// sliced downloads. This is also the number of parts of a parallel
+    /// composite upload that are uploaded concurrently.
+    #[clap(long, default_value = "4")]
+    slices: u64,
+    /// Upload files at least this large with a parallel composite upload,
+    /// which uploads parts of the file concurrently as temporary objects and
+    /// then composes them into the destination object. Composite objects only
+    /// have a crc32c hash and no md5 hash, so this is disabled by default, and
+    /// is never used with --md5.
+    #[clap(long)]
+    composite_threshold: Option<util::ByteSize>,
+    /// The size of each part of a parallel composite upload, which is raised
+    /// for very large files so that they are composed from at most 32 parts
+    #[clap(long, default_value = "50M")]
+    composite_part_size: util::ByteSize,
+    /// Compress files with any of these comma separated extensions with gzip
+    /// while uploading them, storing them with `Content-Encoding: gzip`. GCS
+    /// decompresses such objects for clients that don't accept gzip, but
+    /// they are always downloaded compressed and then decompressed locally.
+    #[clap(short = 'z', long, value_delimiter = ',', conflicts_with = "gzip_all")]
+    gzip_extensions: Vec<String>,
+    /// Compress every file with gzip while uploading it, as --gzip-extensions
+    /// does for files with matching extensions
+    #[clap(short = 'Z', long)]
+    gzip_all: bool,
+    /// The Content-Type of uploaded objects, overriding the type that is
+    /// otherwise detected from the extension of each file
+    #[clap(long)]
+    content_type: Option<String>,
+    /// Detect the Content-Type of files whose extension isn't known from the
+    /// magic bytes at the start of their content
+    #[clap(long)]
+    sniff_content_type: bool,
+    /// Set metadata on uploaded objects, eg. `-h "Cache-Control:no-cache"`.
+    /// The supported headers are Cache-Control, Content-Disposition,
+    /// Content-Encoding, Content-Language, Content-Type, Custom-Time, and
+    /// x-goog-meta-<key> to set custom metadata. Can be repeated.
+    #[clap(short = 'h', long = "header", value_name = "HEADER:VALUE")]
+    headers: Vec<MetadataHeader>,
+    /// Preserve the POSIX attributes of files. Uploads store the mode, uid,
+    /// and gid of each file in the object's metadata, alongside the
+    /// modification time that is always stored, and downloads restore them,
+    /// apart from the owner if we don't have permission to change it.
+    #[clap(short = 'P', long)]
+    preserve_posix: bool,
+}
+
+impl Options {
+    /// Determines if a file is compressed with gzip when it is uploaded
+    pub(crate) fn gzips(&self, path: &Path) -> bool {
+        self.gzip_all
+            || path
+                .extension()
+                .and_then(|ext| ext.to_str())
+                .is_some_and(|ext| {
+                    self.gzip_extensions
+                        .iter()
+                        .any(|gz| gz.trim_start_matches('.').eq_ignore_ascii_case(ext))
+                })
+    }
+
+    /// Determines the Content-Type of an uploaded file. Files that aren't
+    /// recognized are left without one, which GCS serves as
+    /// `application/octet-stream`.
+    fn content_type(&self, path: &Path) -> Option<String> {
+        if let Some(content_type) = &self.content_type {
+            return Some(content_type.clone());
+        }
+
+        mime::from_path(path)
+            .or_else(|| {
+                self.sniff_content_type
+                    .then(|| mime::sniff_file(path).ok().flatten())
+                    .flatten()
+            })
+            .map(str::to_owned)
+    }
+
+    /// Applies the `-h` headers to the metadata of a new object
+    fn apply_headers(&self, md: &mut Metadata) {
+        for header in &self.headers {
+            match header {
+                MetadataHeader::ContentDisposition(value) => {
+                    md.content_disposition = Some(value.clone());
+                }
+                MetadataHeader::ContentEncoding(value) => md.content_encoding = Some(value.clone()),
+                MetadataHeader::ContentLanguage(value) => md.content_language = Some(value.clone()),
+                MetadataHeader::ContentType(value) => md.content_type = Some(value.clone()),
+                MetadataHeader::Custom(key, value) => {
+                    md.metadata
+                        .get_or_insert_with(Default::default)
+                        .insert(key.clone(), value.clone());
+                }
+                MetadataHeader::CacheControl(_) | MetadataHeader::CustomTime(_) => {}
+            }
+        }
+    }
+
+    /// The `-h` headers that tame-gcs' `Metadata` has no fields for
+    fn extra_metadata(&self) -> util::ExtraMetadata {
+        let mut extra = util::ExtraMetadata {
+            storage_class: self.storage_class.map(|sc| sc.as_str().to_owned()),
+            ..Default::default()
+        };
+        for header in &self.headers {
+            match header {
+                MetadataHeader::CacheControl(value) => extra.cache_control = Some(value.clone()),
+                MetadataHeader::CustomTime(value) => extra.custom_time = Some(value.clone()),
+                _ => {}
+            }
+        }
+        extra
+    }
+}
+
+/// Preconditions on the destination object of a copy, which GCS checks
+/// atomically when the object is created
+#[derive(clap::Args, Default)]
+pub struct Preconditions {
+    /// Don't overwrite existing destinations, sources whose destination
+    /// already exists are skipped
+    #[clap(short = 'n', long)]
+    no_clobber: bool,
+    /// Only copy to a destination object whose current generation matches
+    /// this, 0 means the object must not exist
+    #[clap(long, conflicts_with = "no_clobber")]
+    if_generation_match: Option<i64>,
+    /// Only copy to a destination object whose current metageneration
+    /// matches this
+    #[clap(long)]
+    if_metageneration_match: Option<i64>,
+}
+
+impl Preconditions {
+    fn conditionals(&self) -> tame_gcs::common::Conditionals {
+        tame_gcs::common::Conditionals {
+            // A generation of 0 means there must be no live object
+            if_generation_match: if self.no_clobber {
+                Some(0)
+            } else {
+                self.if_generation_match
+            },
+            if_metageneration_match: self.if_metageneration_match,
+            ..Default::default()
+        }
+    }
+
+    /// Determines if a copy failed because the destination already exists
+    /// and shouldn't be overwritten, meaning it should be skipped instead
+    pub(crate) fn is_skipped(&self, err: &anyhow::Error) -> bool {
+        self.no_clobber
+            && util::error_status(err) == Some(tame_gcs::http::StatusCode::PRECONDITION_FAILED)
+    }
+
+    /// Determines if a download should be skipped as the destination file
+    /// already exists and shouldn't be overwritten
+    pub(crate) fn skips_file(&self, dst: &Path) -> bool {
+        self.no_clobber && dst.exists()
+    }
+}
+
+/// Reports a copy that was skipped because its destination already exists
+pub(crate) fn report_skipped(dst: &Location) {
+    eprintln!("Skipping existing item: {dst}");
+}
+
+#[derive(clap::Parser)]
+pub struct Args {
+    #[clap(flatten)]
+    pub(crate) opts: Options,
+    #[clap(flatten)]
+    pub(crate) conditions: Preconditions,
+    #[clap(flatten)]
+    pub(crate) walk: walk::Options,
+    /// Copy an entire directory tree. When uploading, every file beneath the
+    /// source directory is uploaded with its path relative to the directory
+    /// appended to the destination. When downloading, every object beneath
+    /// the source prefix is downloaded, recreating the directory tree locally.
+    #[clap(short = 'r', short_alias = 'R', long)]
+    recursive: bool,
+    /// Don't report the progress of the transfer. By default a progress bar
+    /// is shown if stderr is a terminal, in color unless `NO_COLOR` is set,
+    /// otherwise the progress is printed every 10 seconds.
+    #[clap(long)]
+    no_progress: bool,
+    /// One or more gs: URLs or filepaths for the source paths to copy from.
+    /// gs: URLs may contain wildcards, in which case every matching object is
+    /// copied into the destination directory
+    #[clap(required = true)]
+    src_urls: Vec<String>,
+    /// A gs: URL or filepath for the destination to copy to, wildcards are
+    /// not supported. If it ends with a `/`, is a bucket, or is an existing
+    /// local directory, or if there are multiple sources, it is treated as a
+    /// directory that each source is copied into under its own name.
     dest_url: String,
 }
 
-enum DataPath {
-    Gs(util::GsUrl),
+/// A source or destination as specified by the user, which can refer to any
+/// number of files or objects
+pub(crate) enum DataPath {
+    Gs(wildcard::GsPattern),
     Local(PathBuf),
+    /// `-`, ie. stdin as a source or stdout as a destination
+    Stdio,
 }
 
 impl DataPath {
     #[inline]
-    fn is_local(&self) -> bool {
+    pub(crate) fn is_local(&self) -> bool {
         matches!(self, Self::Local(_))
     }
+
+    /// Ensures that data can be copied from this source to the destination,
+    /// which requires that at least one of them is in GCS
+    pub(crate) fn ensure_copyable_to(&self, dst: &DataPath) -> anyhow::Result<()> {
+        match (self, dst) {
+            (Self::Gs(_), _) | (_, Self::Gs(_)) => Ok(()),
+            (Self::Local(_), Self::Local(_)) => {
+                anyhow::bail!("source and destination are both located on local disk")
+            }
+            _ => anyhow::bail!("stdin and stdout can only be copied to and from gs: URLs"),
+        }
+    }
 }
 
 impl TryFrom<String> for DataPath {
@@ -56,73 +395,1653 @@ impl TryFrom<String> for DataPath {
 
     fn try_from(s: String) -> anyhow::Result<Self> {
         if s.starts_with("gs://") {
-            let url = url::Url::parse(&s)?;
-            Ok(Self::Gs(util::gs_url_to_object_id(&url)?))
+            Ok(Self::Gs(wildcard::GsPattern::parse(&s)?))
+        } else if s == "-" {
+            Ok(Self::Stdio)
         } else {
             Ok(Self::Local(PathBuf::from(s)))
         }
     }
 }
 
+impl std::fmt::Display for DataPath {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Gs(pattern) => pattern.fmt(f),
+            Self::Local(path) => path.display().fmt(f),
+            Self::Stdio => f.write_str("-"),
+        }
+    }
+}
+
+/// A single file or object
+pub(crate) enum Location {
+    Gs(util::GsUrl),
+    Local(PathBuf),
+    Stdio,
+}
+
+impl std::fmt::Display for Location {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Gs(url) => url.fmt(f),
+            Self::Local(path) => path.display().fmt(f),
+            Self::Stdio => f.write_str("-"),
+        }
+    }
+}
+
 // cp is probably gsutil's most complicated subcommand, so we only implement
 // a bare minimum
 pub async fn cmd(ctx: &util::RequestContext, args: Args) -> anyhow::Result<()> {
-    use std::fs;
+    let opts = &args.opts;
+    let conditions = &args.conditions;
+
+    let copies = plan(ctx, &args).await?;
+    let reporter = start_progress(ctx, &args, &copies).await;
 
-    let src = DataPath::try_from(args.src_url)?;
-    let dst = DataPath::try_from(args.dest_url)?;
+    // Objects written to stdout have to be written one at a time
+    let ctx = &util::RequestContext {
+        jobs: if args.dest_url == "-" { 1 } else { ctx.jobs },
+        progress: reporter
+            .as_ref()
+            .map(progress::Reporter::progress)
+            .unwrap_or_default(),
+        ..ctx.clone()
+    };
 
-    // Just support gcs to local or vice versa, not local to local or gcs to gcs
-    if src.is_local() == dst.is_local() {
-        let location = if src.is_local() { "local disk" } else { "gcs" };
+    let res = util::for_each_concurrent(ctx, copies, |PlannedCopy { src, dst, .. }| async move {
+        let res = copy(ctx, opts, conditions, &src, &dst)
+            .await
+            .with_context(|| format!("failed to copy '{src}' to '{dst}'"));
+        ctx.progress.object_done();
+        res
+    })
+    .await;
 
-        anyhow::bail!("source and destination are both located on {location}")
+    if let Some(reporter) = reporter {
+        reporter.finish().await;
     }
 
-    match (&src, &dst) {
-        (DataPath::Local(ref src), DataPath::Gs(dst)) => {
-            let src_file = fs::File::open(src).context("source path")?;
-            let src_len = src_file.metadata()?.len();
+    res
+}
+
+/// A single copy that the sources and destination specified by the user
+/// resolve to
+pub(crate) struct PlannedCopy {
+    pub(crate) src: Location,
+    pub(crate) dst: Location,
+    /// The length of the source, if it was found while resolving it
+    pub(crate) len: Option<u64>,
+}
+
+/// Starts reporting the progress of the copies, unless disabled. The lengths
+/// of sources that weren't found while resolving them, ie. individual objects,
+/// are looked up so that the total is known up front.
+pub(crate) async fn start_progress(
+    ctx: &util::RequestContext,
+    args: &Args,
+    copies: &[PlannedCopy],
+) -> Option<progress::Reporter> {
+    if args.no_progress {
+        return None;
+    }
+
+    use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 
-            let optional = args.predef_acl.map(|acl| objects::InsertObjectOptional {
-                predefined_acl: Some(acl.into()),
+    // The total can't be known if any of the sources is a stream, eg. stdin,
+    // otherwise the lengths of objects that weren't listed are retrieved
+    let total = if copies
+        .iter()
+        .any(|copy| copy.len.is_none() && !matches!(copy.src, Location::Gs(_)))
+    {
+        None
+    } else {
+        let (total, unknown) = (&AtomicU64::new(0), &AtomicBool::new(false));
+
+        // The lookups never fail, an object without a length just leaves the
+        // total unknown
+        let _ = util::for_each_concurrent(ctx, copies, |copy| async move {
+            let len = match (&copy.src, copy.len) {
+                (_, Some(len)) => Some(len),
+                (Location::Gs(url), None) => object_len(ctx, url).await,
+                _ => None,
+            };
+
+            if let Some(len) = len {
+                total.fetch_add(len, Ordering::Relaxed);
+            } else {
+                unknown.store(true, Ordering::Relaxed);
+            }
+            Ok(())
+        })
+        .await;
+
+        (!unknown.load(Ordering::Relaxed)).then(|| total.load(Ordering::Relaxed))
+    };
+
+    Some(progress::Reporter::start(
+        ColorCtx::from_env(),
+        copies.len() as u64,
+        total,
+    ))
+}
+
+/// Retrieves the length of an object, or `None` if that fails, in which case
+/// copying it will fail with the actual error
+async fn object_len(ctx: &util::RequestContext, url: &util::GsUrl) -> Option<u64> {
+    let get_req = ctx
+        .obj
+        .get(
+            &(url.bucket(), url.object()?),
+            Some(objects::GetObjectOptional {
+                standard_params: tame_gcs::common::StandardQueryParameters {
+                    fields: Some("size"),
+                    ..Default::default()
+                },
                 ..Default::default()
+            }),
+        )
+        .ok()?;
+
+    let get_res: objects::GetObjectResponse = util::execute(ctx, get_req).await.ok()?;
+    get_res.metadata.size
+}
+
+/// Resolves the sources and destination specified by the user into each of
+/// the individual copies that need to be made
+pub(crate) async fn plan(
+    ctx: &util::RequestContext,
+    args: &Args,
+) -> anyhow::Result<Vec<PlannedCopy>> {
+    let dst = DataPath::try_from(args.dest_url.clone())?;
+
+    if let DataPath::Gs(dst) = &dst {
+        anyhow::ensure!(
+            !dst.has_wildcard(),
+            "destination '{dst}' can't contain wildcards"
+        );
+    }
+
+    let dir_like = is_dir_like(&args.dest_url, &dst);
+
+    if args.src_urls.len() > 1 {
+        // A gs: destination is just a prefix, so it can always be treated as
+        // a directory, but a local one has to actually be a directory
+        anyhow::ensure!(
+            dir_like || !dst.is_local(),
+            "destination '{}' must be a directory when copying multiple sources",
+            args.dest_url
+        );
+    }
+
+    let dir_like = dir_like || args.src_urls.len() > 1;
+
+    let mut copies = Vec::new();
+    for src_url in &args.src_urls {
+        let src = DataPath::try_from(src_url.clone())?;
+        src.ensure_copyable_to(&dst)?;
+
+        let is_wildcard = matches!(&src, DataPath::Gs(pattern) if pattern.has_wildcard());
+
+        for (src_loc, relative, len) in expand(ctx, args, &src).await? {
+            // When copying into a directory, single sources are placed in it
+            // under their own name, as are the contents of directories
+            let relative = match relative {
+                None if dir_like => Some(basename(&src_loc)?),
+                Some(relative) if dir_like && !is_wildcard => {
+                    Some(format!("{}/{relative}", container_name(&src)?))
+                }
+                relative => relative,
+            };
+
+            copies.push(PlannedCopy {
+                dst: destination(&dst, relative.as_deref())?,
+                src: src_loc,
+                len,
             });
+        }
+    }
+
+    Ok(copies)
+}
+
+/// Determines if the destination, as specified by the user, refers to a
+/// directory, ie. it ends with a `/`, is a bucket, or is an existing local
+/// directory
+fn is_dir_like(url: &str, dst: &DataPath) -> bool {
+    match dst {
+        DataPath::Gs(pattern) => pattern.pattern().is_empty() || pattern.pattern().ends_with('/'),
+        DataPath::Local(path) => {
+            url.ends_with('/') || url.ends_with(std::path::MAIN_SEPARATOR) || path.is_dir()
+        }
+        DataPath::Stdio => false,
+    }
+}
+
+/// The name of a single file or object, without any of its parent
+/// directories or prefixes
+fn basename(loc: &Location) -> anyhow::Result<String> {
+    let name = match loc {
+        Location::Local(path) => path.file_name().and_then(|name| name.to_str()),
+        Location::Gs(url) => url
+            .object()
+            .and_then(|obj| AsRef::<str>::as_ref(obj).rsplit('/').next()),
+        Location::Stdio => anyhow::bail!("stdin can't be copied into a directory"),
+    };
+
+    name.filter(|name| !name.is_empty())
+        .map(ToOwned::to_owned)
+        .with_context(|| format!("'{loc}' doesn't have a file name"))
+}
+
+/// The name of a directory or prefix that is being copied recursively, which
+/// for a whole bucket is the name of the bucket
+fn container_name(src: &DataPath) -> anyhow::Result<String> {
+    let name = match src {
+        DataPath::Local(path) => {
+            // Resolve paths such as `.` to the directory they actually refer to
+            let path = path.canonicalize()?;
+            path.file_name()
+                .and_then(|name| name.to_str())
+                .map(ToOwned::to_owned)
+        }
+        DataPath::Gs(pattern) => {
+            let prefix = pattern.pattern().trim_end_matches('/');
+            if prefix.is_empty() {
+                Some(pattern.bucket().to_string())
+            } else {
+                prefix.rsplit('/').next().map(ToOwned::to_owned)
+            }
+        }
+        DataPath::Stdio => None,
+    };
+
+    name.with_context(|| format!("unable to determine the name of '{src}'"))
+}
+
+/// Ensures a non-empty prefix ends with a `/` so that it only matches objects
+/// "inside" it
+pub(crate) fn dir_prefix(prefix: Option<&tame_gcs::ObjectName<'_>>) -> String {
+    let mut prefix = prefix.map_or("", |on| on.as_ref()).to_owned();
+    if !prefix.is_empty() && !prefix.ends_with('/') {
+        prefix.push('/');
+    }
+    prefix
+}
+
+/// Expands a source into each of the individual files or objects it refers
+/// to. Each is paired with its name relative to the source, which is appended
+/// to the destination, or `None` if the source refers to a single file or
+/// object, in which case the destination is used as is, and with its length
+/// if it was found along the way.
+///
+/// * A local directory, when copying recursively, expands to every file in
+///   the directory tree, relative to the directory
+/// * A gs: URL with wildcards expands to every matching object, relative to
+///   the last `/` in its name
+/// * A gs: URL, when copying recursively, expands to every object beneath it,
+///   relative to the URL. If there are no objects beneath it, it is treated as
+///   a single object.
+async fn expand(
+    ctx: &util::RequestContext,
+    args: &Args,
+    src: &DataPath,
+) -> anyhow::Result<Vec<(Location, Option<String>, Option<u64>)>> {
+    match src {
+        DataPath::Stdio => Ok(vec![(Location::Stdio, None, None)]),
+        DataPath::Local(path) => {
+            if args.recursive && path.is_dir() {
+                Ok(walk::walk(path, &args.walk)?
+                    .into_iter()
+                    .map(|file| {
+                        (
+                            Location::Local(file.path),
+                            Some(file.relative),
+                            Some(file.len),
+                        )
+                    })
+                    .collect())
+            } else {
+                let len = fs::metadata(path).ok().map(|md| md.len());
+                Ok(vec![(Location::Local(path.clone()), None, len)])
+            }
+        }
+        DataPath::Gs(pattern) => {
+            if pattern.has_wildcard() {
+                let bucket = pattern.to_url()?;
+
+                let mut sources = Vec::new();
+                for md in pattern.matches(ctx, Some("name, size")).await? {
+                    let name = md.name.context("object is missing a name")?;
+                    let filename = name.rsplit('/').next().unwrap_or(&name).to_owned();
+
+                    // Placeholder objects for "directories" have no filename
+                    if !filename.is_empty() {
+                        sources.push((
+                            Location::Gs(bucket.with_object(name)?),
+                            Some(filename),
+                            md.size,
+                        ));
+                    }
+                }
+
+                return Ok(sources);
+            }
+
+            let url = pattern.to_url()?;
+
+            if !args.recursive {
+                return Ok(vec![(Location::Gs(url), None, None)]);
+            }
+
+            let prefix = dir_prefix(url.object());
+            let objects =
+                util::list_objects(ctx, url.bucket(), &prefix, None, Some("name, size")).await?;
+
+            // If there is nothing "inside" the source, it is just a regular object
+            if objects.is_empty() && url.object().is_some() {
+                return Ok(vec![(Location::Gs(url), None, None)]);
+            }
+
+            let mut sources = Vec::with_capacity(objects.len());
+            for obj in objects {
+                let name = obj.name.context("object is missing a name")?;
+                let relative = name[prefix.len()..].to_owned();
+
+                // Objects whose name ends with a `/` are placeholders that the
+                // console creates for empty "directories"
+                if relative.is_empty()
+                    || relative.ends_with('/')
+                    || args.walk.is_excluded(&relative)
+                {
+                    continue;
+                }
+
+                sources.push((
+                    Location::Gs(url.with_object(name)?),
+                    Some(relative),
+                    obj.size,
+                ));
+            }
+
+            Ok(sources)
+        }
+    }
+}
+
+/// Determines the destination for a single source, appending the source's
+/// relative name, if it has one, to the destination specified by the user
+pub(crate) fn destination(dst: &DataPath, relative: Option<&str>) -> anyhow::Result<Location> {
+    match dst {
+        // Everything is just written to stdout one after another
+        DataPath::Stdio => Ok(Location::Stdio),
+        DataPath::Local(path) => {
+            let Some(relative) = relative else {
+                return Ok(Location::Local(path.clone()));
+            };
+
+            // Object names can contain anything, so ensure they can't be used
+            // to write outside of the destination directory
+            let mut path = path.clone();
+            for component in relative.split('/') {
+                anyhow::ensure!(
+                    !matches!(component, "" | "." | ".."),
+                    "'{relative}' can't be mapped to a local path"
+                );
+                path.push(component);
+            }
+
+            Ok(Location::Local(path))
+        }
+        DataPath::Gs(pattern) => {
+            let url = pattern.to_url()?;
+
+            match relative {
+                Some(relative) => Ok(Location::Gs(
+                    url.with_object(format!("{}{relative}", dir_prefix(url.object())))?,
+                )),
+                None => Ok(Location::Gs(url)),
+            }
+        }
+    }
+}
+
+/// Copies a single file or object. Copies that are skipped because of `-n`
+/// are reported, but aren't errors.
+pub(crate) async fn copy(
+    ctx: &util::RequestContext,
+    args: &Options,
+    conditions: &Preconditions,
+    src: &Location,
+    dst: &Location,
+) -> anyhow::Result<()> {
+    let res = match (src, dst) {
+        (Location::Local(src), Location::Gs(dst)) => {
+            upload(ctx, args, conditions, src, dst).await.map(|_| ())
+        }
+        (Location::Gs(_), Location::Local(path)) if conditions.skips_file(path) => {
+            report_skipped(dst);
+            Ok(())
+        }
+        (Location::Gs(src), Location::Local(dst)) => download_with_attributes(ctx, args, src, dst)
+            .await
+            .map(|_| ()),
+        (Location::Gs(src), Location::Gs(dst)) => rewrite(ctx, args, conditions, src, dst, None)
+            .await
+            .map(|_| ()),
+        (Location::Stdio, Location::Gs(dst)) => {
+            upload_stdin(ctx, args, conditions, dst).await.map(|_| ())
+        }
+        (Location::Gs(src), Location::Stdio) => download_stdout(ctx, args, src).await,
+        (Location::Local(_), Location::Local(_)) => {
+            anyhow::bail!("source and destination are both located on local disk")
+        }
+        _ => anyhow::bail!("stdin and stdout can only be copied to and from gs: URLs"),
+    };
+
+    match res {
+        Err(err) if conditions.is_skipped(&err) => {
+            report_skipped(dst);
+            Ok(())
+        }
+        res => res,
+    }
+}
+
+/// The optional parameters for inserting an object
+fn insert_optional<'a>(
+    args: &'a Options,
+    conditions: &Preconditions,
+) -> objects::InsertObjectOptional<'a> {
+    objects::InsertObjectOptional {
+        predefined_acl: args.predef_acl.map(Into::into),
+        kms_key_name: args.kms_key.as_deref(),
+        conditionals: conditions.conditionals(),
+        standard_params: tame_gcs::common::StandardQueryParameters {
+            fields: Some(util::OBJECT_FIELDS),
+            ..Default::default()
+        },
+        ..Default::default()
+    }
+}
+
+/// Uploads everything read from stdin until it is closed
+async fn upload_stdin(
+    ctx: &util::RequestContext,
+    args: &Options,
+    conditions: &Preconditions,
+    dst: &util::GsUrl,
+) -> anyhow::Result<Metadata> {
+    let mut metadata = Metadata {
+        name: dst.object().map(|obn| obn.to_string()),
+        content_encoding: Some("identity".to_owned()),
+        content_type: args.content_type.clone(),
+        ..Default::default()
+    };
+    args.apply_headers(&mut metadata);
+
+    let stdin = ctx.progress.reader(std::io::stdin());
+    upload_stream(ctx, args, conditions, dst, &metadata, stdin).await
+}
+
+/// Uploads everything read from a stream until it ends. The length isn't
+/// known up front, so this is always a resumable upload, which is finalized
+/// once the end of the stream is reached. Unlike regular file uploads, the
+/// upload can't be resumed by a later run if it is interrupted.
+async fn upload_stream<R>(
+    ctx: &util::RequestContext,
+    args: &Options,
+    conditions: &Preconditions,
+    dst: &util::GsUrl,
+    metadata: &Metadata,
+    src: R,
+) -> anyhow::Result<Metadata>
+where
+    R: std::io::Read + Send + 'static,
+{
+    let extra = args.extra_metadata();
+    let upload = resumable::Upload {
+        bucket: dst.bucket(),
+        metadata,
+        extra: Some(&extra),
+        optional: Some(insert_optional(args, conditions)),
+        length: None,
+    };
+
+    let session = resumable::start(ctx, &upload).await?;
+
+    let (md, _) =
+        resumable::upload(ctx, &session, src, 0, None, hash::Hasher::new(args.md5)).await?;
+    Ok(md)
+}
+
+/// Writes an object to stdout as it is downloaded. The content is verified
+/// once it has all been written, but unlike a file it can't be taken back if
+/// it doesn't match
+async fn download_stdout(
+    ctx: &util::RequestContext,
+    args: &Options,
+    src: &util::GsUrl,
+) -> anyhow::Result<()> {
+    let (response, info) = start_download(ctx, src, None).await?;
+    let expected = hash::ObjectHashes::from_headers(response.headers());
+    let md5 = args.md5 || expected.crc32c.is_none();
+    let stdout = std::io::stdout().lock();
+
+    // The hashes are of the content as it is stored, so gzipped objects are
+    // hashed before they are decompressed
+    let hashes = if info.gzipped {
+        let mut writer = hash::HashWriter::new(flate2::write::MultiGzDecoder::new(stdout), md5);
+        response.copy_to(&mut writer).await?;
+        writer
+            .get_mut()
+            .try_finish()
+            .context("failed to decompress object")?;
+        writer.finish()
+    } else {
+        let mut writer = hash::HashWriter::new(stdout, md5);
+        response.copy_to(&mut writer).await?;
+        writer.finish()
+    };
+
+    hashes
+        .verify(&expected)
+        .context("downloaded content doesn't match the object")
+}
+
+/// Uploads a single local file
+pub(crate) async fn upload(
+    ctx: &util::RequestContext,
+    args: &Options,
+    conditions: &Preconditions,
+    src: &Path,
+    dst: &util::GsUrl,
+) -> anyhow::Result<Metadata> {
+    let mut src_file = fs::File::open(src).context("source path")?;
+    let src_md = src_file.metadata()?;
+    let src_len = src_md.len();
+
+    let optional = Some(insert_optional(args, conditions));
+
+    let mut metadata = Metadata {
+        name: dst.object().map(|obn| obn.to_string()),
+        content_encoding: Some("identity".to_owned()),
+        content_type: args.content_type(src),
+        ..Default::default()
+    };
+    args.apply_headers(&mut metadata);
+
+    if let Some(mtime) = util::unix_mtime(&src_md) {
+        metadata
+            .metadata
+            .get_or_insert_with(Default::default)
+            .insert(posix::MTIME_KEY.to_owned(), mtime.to_string());
+    }
+
+    if args.preserve_posix {
+        metadata
+            .metadata
+            .get_or_insert_with(Default::default)
+            .extend(
+                posix::attributes(&src_md)
+                    .into_iter()
+                    .map(|(key, value)| (key.to_owned(), value)),
+            );
+    }
+
+    let extra = args.extra_metadata();
+
+    if args.gzips(src) {
+        // The file is compressed as it is read, so the hashes that are sent
+        // and verified are those of the compressed content, which is what
+        // GCS stores
+        metadata.content_encoding = Some("gzip".to_owned());
+        let src_file = flate2::read::GzEncoder::new(
+            ctx.progress.reader(src_file),
+            flate2::Compression::default(),
+        );
+
+        return upload_stream(ctx, args, conditions, dst, &metadata, src_file).await;
+    }
+
+    if !args.md5
+        && args
+            .composite_threshold
+            .is_some_and(|threshold| src_len >= threshold.0.max(1))
+    {
+        return composite_upload(ctx, args, conditions, src, src_len, dst, &metadata).await;
+    }
+
+    // tame-gcs' multipart uploads can only send the metadata it knows about
+    if !args.resumable && src_len < args.resumable_threshold.0 && extra.is_empty() {
+        // The file is small, so just hash it up front so that the hashes can
+        // be sent along with the rest of the metadata for GCS to validate
+        let hashes = {
+            let src = src.to_owned();
+            let md5 = args.md5;
+            tokio::task::spawn_blocking(move || hash::hash_file(&src, md5)).await??
+        };
+
+        metadata.crc32c = Some(hashes.crc32c.clone());
+        metadata.md5_hash = hashes.md5.clone();
+
+        let mut insert_req = ctx.obj.insert_multipart(
+            dst.bucket(),
+            ctx.progress.reader(src_file),
+            src_len,
+            &metadata,
+            optional,
+        )?;
+        encryption::add_headers(insert_req.headers_mut(), ctx.encryption_keys.encryption())?;
+
+        let insert_res: objects::InsertResponse = util::execute(ctx, insert_req).await?;
+
+        hashes
+            .verify(&hash::ObjectHashes::from_metadata(&insert_res.metadata))
+            .context("uploaded object doesn't match the source")?;
 
-            let insert_req = ctx.obj.insert_multipart(
+        return Ok(insert_res.metadata);
+    }
+
+    let upload = resumable::Upload {
+        bucket: dst.bucket(),
+        metadata: &metadata,
+        extra: Some(&extra),
+        optional,
+        length: Some(src_len),
+    };
+
+    let tracker = resumable::Tracker::new(ctx, src, &upload)?;
+
+    let uploaded = match resumable::resume_or_start(ctx, &upload, &tracker).await? {
+        resumable::Resume::Session(session, offset) => {
+            // Hash the part of the file the server already has, which also
+            // positions the file at the point the upload resumes from
+            let (src_file, hasher) = tokio::task::spawn_blocking({
+                let md5 = args.md5;
+                move || -> anyhow::Result<_> {
+                    use std::io::Read;
+
+                    let mut hasher = hash::Hasher::new(md5);
+                    std::io::copy(&mut (&mut src_file).take(offset), &mut hasher)
+                        .context("failed to read source")?;
+                    Ok((src_file, hasher))
+                }
+            })
+            .await??;
+
+            ctx.progress.add(offset);
+            let src_file = ctx.progress.reader(src_file);
+
+            resumable::upload(ctx, &session, src_file, offset, Some(src_len), hasher)
+                .await?
+                .0
+        }
+        resumable::Resume::Complete(md) => {
+            // An earlier run finished the upload but was interrupted before it
+            // could verify it
+            let src = src.to_owned();
+            let md5 = args.md5;
+            tokio::task::spawn_blocking(move || hash::hash_file(&src, md5))
+                .await??
+                .verify(&hash::ObjectHashes::from_metadata(&md))
+                .context("uploaded object doesn't match the source")?;
+
+            ctx.progress.add(src_len);
+            *md
+        }
+    };
+
+    tracker.remove();
+
+    Ok(uploaded)
+}
+
+/// Uploads a file as a parallel composite upload. The file is split into
+/// parts that are uploaded concurrently as temporary objects in the
+/// destination bucket, which are then composed into the destination object.
+/// The temporary objects are always removed afterwards, whether or not the
+/// upload succeeded.
+async fn composite_upload(
+    ctx: &util::RequestContext,
+    args: &Options,
+    conditions: &Preconditions,
+    src: &Path,
+    src_len: u64,
+    dst: &util::GsUrl,
+    metadata: &Metadata,
+) -> anyhow::Result<Metadata> {
+    use futures_util::StreamExt as _;
+    use std::hash::{BuildHasher as _, Hasher as _};
+
+    let max_sources = compose::MAX_SOURCES as u64;
+    let part_size = args
+        .composite_part_size
+        .0
+        .max(src_len.div_ceil(max_sources))
+        .max(1);
+
+    // Keep the parts of concurrent uploads of the same file apart
+    let nonce = std::collections::hash_map::RandomState::new()
+        .build_hasher()
+        .finish();
+
+    let results: Vec<_> =
+        futures_util::stream::iter((0..src_len).step_by(part_size as usize).enumerate().map(
+            |(i, start)| {
+                let name = format!("{COMPOSITE_PART_PREFIX}{nonce:016x}/{i}");
+                upload_part(
+                    ctx,
+                    args,
+                    src,
+                    dst,
+                    name,
+                    start..(start + part_size).min(src_len),
+                )
+            },
+        ))
+        .buffered(args.slices.max(1) as usize)
+        .collect()
+        .await;
+
+    // The crc32c of the composed object is checked against the crc32c of
+    // every part combined in order
+    let mut parts = Vec::with_capacity(results.len());
+    let mut combined: Option<hash::Hasher> = None;
+    let mut failed = None;
+    for (res, start) in results
+        .into_iter()
+        .zip((0..src_len).step_by(part_size as usize))
+    {
+        match res {
+            Ok((part, hasher)) => {
+                let len = (src_len - start).min(part_size);
+                combined = Some(match combined {
+                    Some(combined) => combined.combine(hasher, len),
+                    None => hasher,
+                });
+                parts.push(part);
+            }
+            Err(err) => {
+                failed.get_or_insert(err);
+            }
+        }
+    }
+
+    let composed = match failed {
+        Some(err) => Err(err),
+        None => {
+            compose::compose(
+                ctx,
+                dst,
+                &parts,
+                metadata,
+                compose::ComposeOptional {
+                    destination_predefined_acl: args.predef_acl.map(Acl::as_str),
+                    kms_key_name: args.kms_key.as_deref(),
+                    conditionals: conditions.conditionals(),
+                    extra_metadata: Some(&args.extra_metadata()),
+                },
+            )
+            .await
+        }
+    };
+
+    let cleanup = util::for_each_concurrent(ctx, &parts, |part| async move {
+        let del_req = ctx.obj.delete(
+            &(
                 dst.bucket(),
-                src_file,
-                src_len,
-                &Metadata {
-                    name: dst.object().map(|obn| obn.to_string()),
-                    content_encoding: Some("identity".to_owned()),
+                &tame_gcs::ObjectName::try_from(part.name.as_str())?,
+            ),
+            Some(objects::DeleteObjectOptional {
+                generation: Some(part.generation),
+                ..Default::default()
+            }),
+        )?;
+
+        util::execute::<_, objects::DeleteObjectResponse>(ctx, del_req)
+            .await
+            .with_context(|| format!("failed to remove temporary part '{}'", part.name))?;
+        Ok(())
+    })
+    .await;
+
+    let composed = composed?;
+    cleanup.context("failed to remove the temporary parts of the composite upload")?;
+
+    anyhow::ensure!(
+        composed.size == Some(src_len),
+        "composed object is {:?} bytes, but the source is {src_len} bytes",
+        composed.size
+    );
+
+    combined
+        .unwrap_or_else(|| hash::Hasher::new(false))
+        .finish()
+        .verify(&hash::ObjectHashes::from_metadata(&composed))
+        .context("composed object doesn't match the source")?;
+
+    Ok(composed)
+}
+
+/// Uploads a byte range of a file as a temporary object for a parallel
+/// composite upload, returning the hasher of the range along with the part
+async fn upload_part(
+    ctx: &util::RequestContext,
+    args: &Options,
+    src: &Path,
+    dst: &util::GsUrl,
+    name: String,
+    range: std::ops::Range<u64>,
+) -> anyhow::Result<(compose::Source, hash::Hasher)> {
+    use std::io::{Read as _, Seek as _};
+
+    let len = range.end - range.start;
+
+    let mut src_file = fs::File::open(src).context("source path")?;
+    src_file.seek(std::io::SeekFrom::Start(range.start))?;
+
+    let metadata = Metadata {
+        name: Some(name.clone()),
+        ..Default::default()
+    };
+
+    let upload = resumable::Upload {
+        bucket: dst.bucket(),
+        metadata: &metadata,
+        extra: None,
+        // Buckets may require objects to be encrypted with a specific key, so
+        // the parts use the same key as the destination
+        optional: Some(objects::InsertObjectOptional {
+            kms_key_name: args.kms_key.as_deref(),
+            standard_params: tame_gcs::common::StandardQueryParameters {
+                fields: Some(util::OBJECT_FIELDS),
+                ..Default::default()
+            },
+            ..Default::default()
+        }),
+        length: Some(len),
+    };
+
+    let session = resumable::start(ctx, &upload).await?;
+    let (uploaded, hasher) = resumable::upload(
+        ctx,
+        &session,
+        ctx.progress.reader(src_file.take(len)),
+        0,
+        Some(len),
+        hash::Hasher::new(false),
+    )
+    .await
+    .with_context(|| format!("failed to upload part at offset {}", range.start))?;
+
+    let part = compose::Source {
+        name,
+        generation: uploaded
+            .generation
+            .context("uploaded part is missing its generation")?,
+    };
+
+    Ok((part, hasher))
+}
+
+/// Copies an object to another location in GCS via the rewrite API, so that
+/// none of the data has to pass through this machine. Copies between
+/// different locations or storage classes can take more than one call, so the
+/// rewrite is repeated until GCS reports it is done. If `source_generation`
+/// is specified, that exact generation of the source is copied.
+pub(crate) async fn rewrite(
+    ctx: &util::RequestContext,
+    args: &Options,
+    conditions: &Preconditions,
+    src: &util::GsUrl,
+    dst: &util::GsUrl,
+    source_generation: Option<i64>,
+) -> anyhow::Result<Metadata> {
+    let src_id = (
+        src.bucket(),
+        src.object()
+            .context("must provide a full object name to copy from")?,
+    );
+    let dst_id = (
+        dst.bucket(),
+        dst.object()
+            .context("must provide a full object name to copy to")?,
+    );
+
+    // Any metadata in the request replaces all of the source object's
+    // metadata, so if we need to change the storage class we have to send the
+    // source's metadata along with it
+    let body = match args.storage_class {
+        Some(storage_class) => {
+            let get_req = ctx.obj.get(
+                &src_id,
+                Some(objects::GetObjectOptional {
+                    generation: source_generation,
+                    standard_params: tame_gcs::common::StandardQueryParameters {
+                        fields: Some(WRITABLE_METADATA_FIELDS),
+                        ..Default::default()
+                    },
                     ..Default::default()
-                },
-                optional,
+                }),
             )?;
 
-            let _insert_res: objects::InsertResponse = util::execute(ctx, insert_req).await?;
+            let util::JsonResponse(mut md) = util::execute(ctx, get_req).await?;
+            md["storageClass"] = storage_class.as_str().into();
 
-            Ok(())
+            Some(serde_json::to_vec(&md)?)
         }
-        (DataPath::Gs(src), DataPath::Local(dst)) => {
-            let mut dst_file = fs::File::create(dst).context("destination path")?;
+        None => None,
+    };
 
-            let dl_req = ctx.obj.download(
-                &(
-                    src.bucket(),
-                    src.object()
-                        .context("must provide a full object name to copy from")?,
-                ),
-                None,
-            )?;
+    let src_key = encryption::key_for(ctx, src, source_generation).await?;
+    let fields = format!(
+        "done, rewriteToken, totalBytesRewritten, objectSize, resource({})",
+        util::OBJECT_FIELDS
+    );
+    let mut rewrite_token = None;
+    let mut rewritten = 0;
 
-            let mut response: objects::DownloadObjectResponse = util::execute(ctx, dl_req).await?;
+    loop {
+        let mut rewrite_req = ctx.obj.rewrite(
+            &src_id,
+            &dst_id,
+            rewrite_token.take(),
+            None,
+            Some(objects::RewriteObjectOptional {
+                destination_kms_key_name: args.kms_key.clone(),
+                destination_predefined_acl: args.predef_acl.map(|acl| acl.as_str().to_owned()),
+                destination_conditionals: Some(conditions.conditionals()),
+                source_generation,
+                standard_params: tame_gcs::common::StandardQueryParameters {
+                    fields: Some(&fields),
+                    ..Default::default()
+                },
+                ..Default::default()
+            }),
+        )?;
+
+        encryption::add_copy_source_headers(rewrite_req.headers_mut(), src_key.as_ref())?;
+        encryption::add_headers(rewrite_req.headers_mut(), ctx.encryption_keys.encryption())?;
+
+        if let Some(body) = &body {
+            let headers = rewrite_req.headers_mut();
+            headers.insert(
+                tame_gcs::http::header::CONTENT_TYPE,
+                tame_gcs::http::HeaderValue::from_static("application/json"),
+            );
+            headers.insert(tame_gcs::http::header::CONTENT_LENGTH, body.len().into());
+            *rewrite_req.body_mut() = std::io::Cursor::new(body.clone());
+        }
+
+        let rewrite_res: objects::RewriteObjectResponse = util::execute(ctx, rewrite_req).await?;
+
+        // The data never passes through us, but it is still transferred
+        ctx.progress
+            .add(rewrite_res.total_bytes_rewritten.saturating_sub(rewritten));
+        rewritten = rewrite_res.total_bytes_rewritten;
+
+        if rewrite_res.done {
+            return rewrite_res
+                .metadata
+                .context("rewrite response is missing the object metadata");
+        }
+
+        rewrite_token = Some(
+            rewrite_res
+                .rewrite_token
+                .context("incomplete rewrite response is missing the rewrite token")?,
+        );
+    }
+}
+
+/// Creates a request to download an object, optionally only the specified
+/// byte range of it. If `compressed` is set, objects stored with gzip are
+/// downloaded as is, rather than being decompressed by GCS. `key` is the
+/// customer-supplied key the object is encrypted with, if any.
+fn download_request(
+    ctx: &util::RequestContext,
+    src: &util::GsUrl,
+    generation: Option<i64>,
+    range: Option<std::ops::Range<u64>>,
+    compressed: bool,
+    key: Option<&encryption::Key>,
+) -> anyhow::Result<tame_gcs::http::Request<std::io::Empty>> {
+    let mut dl_req = ctx.obj.download(
+        &(
+            src.bucket(),
+            src.object()
+                .context("must provide a full object name to copy from")?,
+        ),
+        Some(objects::DownloadObjectOptional {
+            generation,
+            ..Default::default()
+        }),
+    )?;
+
+    if let Some(range) = range {
+        // A range ending at u64::MAX is open ended, ie. the rest of the object
+        let range = if range.end == u64::MAX {
+            format!("bytes={}-", range.start)
+        } else {
+            format!("bytes={}-{}", range.start, range.end - 1)
+        };
+
+        dl_req.headers_mut().insert(
+            tame_gcs::http::header::RANGE,
+            tame_gcs::http::HeaderValue::try_from(range)?,
+        );
+    }
+
+    if compressed {
+        dl_req.headers_mut().insert(
+            tame_gcs::http::header::ACCEPT_ENCODING,
+            tame_gcs::http::HeaderValue::from_static("gzip"),
+        );
+    }
+
+    encryption::add_headers(dl_req.headers_mut(), key)?;
+
+    Ok(dl_req)
+}
 
-            std::io::copy(&mut response, &mut dst_file)?;
+/// Starts downloading an object. GCS decompresses objects stored with gzip
+/// on the fly, but the hashes it has are of the compressed content, and the
+/// decompressed content can't be requested in byte ranges, so such downloads
+/// are restarted to download the compressed content instead, which is
+/// decompressed locally.
+///
+/// Compressed content isn't requested up front as GCS may then compress the
+/// responses for objects that aren't stored compressed.
+async fn start_download(
+    ctx: &util::RequestContext,
+    src: &util::GsUrl,
+    generation: Option<i64>,
+) -> anyhow::Result<(util::StreamingResponse, ObjectInfo)> {
+    let key = encryption::key_for(ctx, src, generation).await?;
 
+    let response = util::execute_streaming(
+        ctx,
+        download_request(ctx, src, generation, None, false, key.as_ref())?,
+    )
+    .await?;
+    let info = ObjectInfo::from_headers(response.headers(), key.clone())?;
+
+    if !info.transcoded {
+        return Ok((response, info));
+    }
+
+    drop(response);
+
+    let response = util::execute_streaming(
+        ctx,
+        download_request(ctx, src, Some(info.generation), None, true, key.as_ref())?,
+    )
+    .await?;
+    let info = ObjectInfo::from_headers(response.headers(), key)?;
+
+    Ok((response, info))
+}
+
+/// The suffix of the temporary files that downloads are written to before
+/// they are complete
+const PARTIAL_SUFFIX: &str = ".gstmp";
+
+/// The details of an object revealed by the headers of its download response
+struct ObjectInfo {
+    /// The length of the content being downloaded
+    len: Option<u64>,
+    generation: i64,
+    /// True if the object is stored compressed with gzip
+    gzipped: bool,
+    /// True if GCS is decompressing the object on the fly, in which case the
+    /// content can't be requested in byte ranges
+    transcoded: bool,
+    /// The customer-supplied key the object is encrypted with, which every
+    /// request for its content has to include
+    key: Option<encryption::Key>,
+}
+
+impl ObjectInfo {
+    fn from_headers(
+        headers: &tame_gcs::http::HeaderMap,
+        key: Option<encryption::Key>,
+    ) -> anyhow::Result<Self> {
+        let header = |name: &str| headers.get(name).and_then(|hv| hv.to_str().ok());
+        let stored_encoding = header("x-goog-stored-content-encoding");
+
+        Ok(Self {
+            len: header("content-length").and_then(|len| len.parse().ok()),
+            generation: header("x-goog-generation")
+                .and_then(|gen| gen.parse().ok())
+                .context("download response is missing the object's generation")?,
+            gzipped: stored_encoding == Some("gzip"),
+            transcoded: stored_encoding.is_some_and(|ce| ce != "identity")
+                && header("content-encoding") != stored_encoding,
+            key,
+        })
+    }
+}
+
+/// The path of the temporary sibling file that a download of the specified
+/// generation is written to, which includes the generation so that a partial
+/// download is only ever resumed if the object hasn't changed since. `kind`
+/// distinguishes temporary files that can't be resumed, eg. `sliced`.
+fn partial_path(dst: &Path, generation: i64, kind: Option<&str>) -> anyhow::Result<PathBuf> {
+    let filename = dst
+        .file_name()
+        .and_then(|name| name.to_str())
+        .with_context(|| format!("'{}' is not a valid file path", dst.display()))?;
+
+    Ok(dst.with_file_name(partial_name(filename, generation, kind)))
+}
+
+/// The kinds of partial files other than regular downloads
+const PARTIAL_KINDS: &[&str] = &["sliced", "gunzip"];
+
+/// The name of a partial file for a download of the specified generation of
+/// an object to `filename`
+fn partial_name(filename: &str, generation: i64, kind: Option<&str>) -> String {
+    let kind = kind.map(|kind| format!(".{kind}")).unwrap_or_default();
+    format!(".{filename}.{generation}{kind}{PARTIAL_SUFFIX}")
+}
+
+/// Determines if `name` is a partial file for a download of any generation of
+/// an object to `filename`. The name has to be exactly one that
+/// [`partial_name`] produces, so the partial files of other destinations that
+/// merely start with the same name, eg. `app.log.1` vs `app.log`, don't match.
+fn is_partial_of(name: &str, filename: &str) -> bool {
+    let Some(rest) = name
+        .strip_prefix('.')
+        .and_then(|rest| rest.strip_prefix(filename))
+        .and_then(|rest| rest.strip_prefix('.'))
+    else {
+        return false;
+    };
+
+    let digits = rest
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(rest.len());
+    let Ok(generation) = rest[..digits].parse::<i64>() else {
+        return false;
+    };
+
+    std::iter::once(None)
+        .chain(PARTIAL_KINDS.iter().copied().map(Some))
+        .any(|kind| partial_name(filename, generation, kind) == name)
+}
+
+/// Removes any partial downloads for the destination other than the
+/// specified one, which are left over from earlier generations of the object
+fn remove_stale_partials(dst: &Path, keep: &Path) {
+    let (Some(dir), Some(filename)) = (dst.parent(), dst.file_name().and_then(|n| n.to_str()))
+    else {
+        return;
+    };
+    let dir = if dir.as_os_str().is_empty() {
+        Path::new(".")
+    } else {
+        dir
+    };
+    let Ok(entries) = fs::read_dir(dir) else {
+        return;
+    };
+
+    for entry in entries.flatten() {
+        let name = entry.file_name();
+        let Some(name) = name.to_str() else {
+            continue;
+        };
+
+        if is_partial_of(name, filename) && entry.path().file_name() != keep.file_name() {
+            let _ = fs::remove_file(entry.path());
+        }
+    }
+}
+
+/// Downloads a single object to a local file, creating any missing parent
+/// directories. If `generation` is specified, that exact generation of the
+/// object is downloaded. The content is hashed as it is written and checked
+/// against the hashes GCS has for the object, removing the file if they don't
+/// match. Returns the number of bytes of the object that were downloaded.
+///
+/// The object is written to a temporary sibling file that is only renamed to
+/// the destination once it is complete and verified. If a download is
+/// interrupted, the next download of the same generation of the object
+/// continues where the partial file ends, while a partial file for another
+/// generation is discarded.
+///
+/// Objects larger than [`Options::sliced_threshold`] are downloaded in slices
+/// instead, once the response to the regular download has revealed their size.
+///
+/// Objects stored with gzip are downloaded, and verified, as they are stored,
+/// and are only decompressed into the destination once they are complete.
+pub(crate) async fn download(
+    ctx: &util::RequestContext,
+    args: &Options,
+    src: &util::GsUrl,
+    dst: &Path,
+    generation: Option<i64>,
+) -> anyhow::Result<u64> {
+    let (response, info) = start_download(ctx, src, generation).await?;
+    let expected = hash::ObjectHashes::from_headers(response.headers());
+
+    if let Some(parent) = dst.parent().filter(|p| !p.as_os_str().is_empty()) {
+        fs::create_dir_all(parent)
+            .with_context(|| format!("failed to create '{}'", parent.display()))?;
+    }
+
+    let partial = partial_path(dst, info.generation, None)?;
+    remove_stale_partials(dst, &partial);
+
+    // Composite objects only have a crc32c, so the md5 is only needed if
+    // explicitly requested or the object somehow doesn't have a crc32c
+    let md5 = args.md5 || expected.crc32c.is_none();
+
+    let resume_from = fs::metadata(&partial)
+        .ok()
+        .map(|md| md.len())
+        .filter(|&len| len > 0 && !info.transcoded && info.len.is_some_and(|total| len <= total));
+
+    let (written, partial) = if let Some(offset) = resume_from {
+        // Abandon the regular download, we only need the rest of the object
+        drop(response);
+
+        let written = resume_download(ctx, src, &partial, &info, offset, &expected, md5).await;
+        (written, partial)
+    } else if let Some(len) = sliced_len(args, &info, &expected) {
+        // Abandon the regular download now that we know the object is large
+        // enough to be sliced
+        drop(response);
+
+        // Sliced downloads are written out of order, so they can't be resumed
+        // from the length of the file, and are removed if they fail
+        let partial = partial_path(dst, info.generation, Some("sliced"))?;
+        let written = async {
+            fs::File::create(&partial)
+                .context("destination path")?
+                .set_len(len)
+                .context("failed to preallocate destination")?;
+
+            download_slices(ctx, args, src, &partial, len, &info, &expected).await
+        }
+        .await;
+
+        if written.is_err() {
+            let _ = fs::remove_file(&partial);
+        }
+
+        (written, partial)
+    } else {
+        let written = async {
+            let partial_file = fs::File::create(&partial).context("destination path")?;
+            let mut writer = hash::HashWriter::new(partial_file, md5);
+
+            let written = response.copy_to(&mut writer).await?;
+            verify_download(writer.finish(), &expected, &partial)?;
+            Ok(written)
+        }
+        .await;
+
+        (written, partial)
+    };
+
+    let written = written?;
+
+    if info.gzipped {
+        gunzip(&partial, dst, info.generation).await?;
+    } else {
+        fs::rename(&partial, dst)
+            .with_context(|| format!("failed to move download into '{}'", dst.display()))?;
+    }
+
+    Ok(written)
+}
+
+/// Decompresses a complete download of a gzipped object into the
+/// destination, via another temporary file so that the destination is only
+/// ever replaced by the complete content
+async fn gunzip(partial: &Path, dst: &Path, generation: i64) -> anyhow::Result<()> {
+    let decompressed = partial_path(dst, generation, Some("gunzip"))?;
+
+    let res = tokio::task::spawn_blocking({
+        let partial = partial.to_owned();
+        let decompressed = decompressed.clone();
+        move || -> anyhow::Result<()> {
+            let mut decoder = flate2::read::MultiGzDecoder::new(std::io::BufReader::new(
+                fs::File::open(partial)?,
+            ));
+            let mut dst_file = fs::File::create(decompressed).context("destination path")?;
+            std::io::copy(&mut decoder, &mut dst_file).context("failed to decompress object")?;
             Ok(())
         }
-        _ => unreachable!(),
+    })
+    .await?;
+
+    if let Err(err) = res {
+        let _ = fs::remove_file(&decompressed);
+        return Err(err);
+    }
+
+    fs::rename(&decompressed, dst)
+        .with_context(|| format!("failed to move download into '{}'", dst.display()))?;
+    let _ = fs::remove_file(partial);
+
+    Ok(())
+}
+
+/// Downloads an object and restores the modification time, and POSIX
+/// attributes if they are being preserved, stored in its metadata to the
+/// file. The metadata is retrieved first, so that exactly the same generation
+/// of the object is downloaded.
+async fn download_with_attributes(
+    ctx: &util::RequestContext,
+    args: &Options,
+    src: &util::GsUrl,
+    dst: &Path,
+) -> anyhow::Result<u64> {
+    let get_req = ctx.obj.get(
+        &(
+            src.bucket(),
+            src.object()
+                .context("must provide a full object name to copy from")?,
+        ),
+        Some(objects::GetObjectOptional {
+            standard_params: tame_gcs::common::StandardQueryParameters {
+                fields: Some("generation, metadata"),
+                ..Default::default()
+            },
+            ..Default::default()
+        }),
+    )?;
+    let get_res: objects::GetObjectResponse = util::execute(ctx, get_req).await?;
+    let md = get_res.metadata;
+
+    let written = download(ctx, args, src, dst, md.generation).await?;
+    restore_attributes(args, dst, &md)?;
+
+    Ok(written)
+}
+
+/// Restores the modification time stored in an object's metadata to the file
+/// it was downloaded to, the same as gsutil, along with the rest of the POSIX
+/// attributes if they are being preserved
+pub(crate) fn restore_attributes(args: &Options, dst: &Path, md: &Metadata) -> anyhow::Result<()> {
+    let Some(custom) = &md.metadata else {
+        return Ok(());
+    };
+
+    let res = if args.preserve_posix {
+        posix::restore(dst, custom)
+    } else {
+        posix::restore_mtime(dst, custom)
+    };
+
+    res.with_context(|| format!("failed to restore the attributes of '{}'", dst.display()))
+}
+
+/// Continues a download into an existing partial file of the same generation,
+/// requesting only the remainder of the object after `offset`
+async fn resume_download(
+    ctx: &util::RequestContext,
+    src: &util::GsUrl,
+    partial: &Path,
+    info: &ObjectInfo,
+    offset: u64,
+    expected: &hash::ObjectHashes,
+    md5: bool,
+) -> anyhow::Result<u64> {
+    // Hash what we already have, which also positions the file at its end
+    let (partial_file, hasher) = tokio::task::spawn_blocking({
+        let partial = partial.to_owned();
+        move || -> anyhow::Result<_> {
+            let mut partial_file = fs::OpenOptions::new()
+                .read(true)
+                .append(true)
+                .open(&partial)
+                .context("destination path")?;
+
+            let mut hasher = hash::Hasher::new(md5);
+            std::io::copy(&mut partial_file, &mut hasher)
+                .context("failed to read partial download")?;
+            Ok((partial_file, hasher))
+        }
+    })
+    .await??;
+
+    let mut writer = hash::HashWriter::with_hasher(partial_file, hasher);
+    let mut written = offset;
+    ctx.progress.add(offset);
+
+    // The partial file may actually be complete if we were interrupted
+    // before it could be moved into place
+    if info.len != Some(offset) {
+        let response = util::execute_streaming(
+            ctx,
+            download_request(
+                ctx,
+                src,
+                Some(info.generation),
+                Some(offset..u64::MAX),
+                info.gzipped,
+                info.key.as_ref(),
+            )?,
+        )
+        .await?;
+
+        written += response.copy_to(&mut writer).await?;
+    }
+
+    verify_download(writer.finish(), expected, partial)?;
+    Ok(written)
+}
+
+/// Verifies the hashes of a download, removing the partial file if they don't
+/// match as it can't be resumed
+fn verify_download(
+    hashes: hash::Hashes,
+    expected: &hash::ObjectHashes,
+    partial: &Path,
+) -> anyhow::Result<()> {
+    if let Err(err) = hashes.verify(expected) {
+        let _ = fs::remove_file(partial);
+        return Err(err.context("downloaded content doesn't match the object"));
+    }
+
+    Ok(())
+}
+
+/// Determines if a download should be sliced, returning the length of the
+/// object if it should. Objects that GCS decompresses on the fly can't be
+/// sliced as byte ranges of them aren't supported, nor can objects without a
+/// crc32c, as it is the only hash that can be calculated from the individual
+/// slices.
+fn sliced_len(args: &Options, info: &ObjectInfo, expected: &hash::ObjectHashes) -> Option<u64> {
+    if args.slices < 2 || expected.crc32c.is_none() || info.transcoded {
+        return None;
+    }
+
+    let len = info.len?;
+    (len >= args.sliced_threshold.0 && len >= args.slices).then_some(len)
+}
+
+/// Downloads an object in [`Options::slices`] byte ranges concurrently, each of
+/// which is written to its offset in the destination file. Every slice is
+/// hashed separately, and their hashes combined to verify the crc32c of the
+/// entire object. The destination file must already be `len` bytes long.
+async fn download_slices(
+    ctx: &util::RequestContext,
+    args: &Options,
+    src: &util::GsUrl,
+    dst: &Path,
+    len: u64,
+    info: &ObjectInfo,
+    expected: &hash::ObjectHashes,
+) -> anyhow::Result<u64> {
+    use futures_util::{StreamExt as _, TryStreamExt as _};
+
+    let slice_len = len.div_ceil(args.slices);
+    let ranges: Vec<_> = (0..len)
+        .step_by(slice_len as usize)
+        .map(|start| start..(start + slice_len).min(len))
+        .collect();
+
+    // Slices finish in any order, but they have to be combined in order
+    let mut hashers: Vec<(u64, hash::Hasher)> =
+        futures_util::stream::iter(ranges.into_iter().map(|range| async move {
+            let start = range.start;
+            let hasher = download_slice(ctx, src, dst, info, range)
+                .await
+                .with_context(|| format!("failed to download slice at offset {start}"))?;
+            anyhow::Ok((start, hasher))
+        }))
+        .buffer_unordered(args.slices as usize)
+        .try_collect()
+        .await?;
+
+    hashers.sort_by_key(|(start, _)| *start);
+
+    let hasher = hashers
+        .into_iter()
+        .map(|(start, hasher)| (hasher, (start + slice_len).min(len) - start))
+        .reduce(|(first, first_len), (next, next_len)| {
+            (first.combine(next, next_len), first_len + next_len)
+        })
+        .map(|(hasher, _)| hasher)
+        .context("object has no slices")?;
+
+    hasher
+        .finish()
+        .verify(expected)
+        .context("downloaded content doesn't match the object")?;
+
+    Ok(len)
+}
+
+/// Downloads a single byte range of an object into the same range of the
+/// destination file, returning the hasher for the range
+async fn download_slice(
+    ctx: &util::RequestContext,
+    src: &util::GsUrl,
+    dst: &Path,
+    info: &ObjectInfo,
+    range: std::ops::Range<u64>,
+) -> anyhow::Result<hash::Hasher> {
+    use std::io::Seek as _;
+
+    let expected_len = range.end - range.start;
+    let start = range.start;
+
+    let response = util::execute_streaming(
+        ctx,
+        download_request(
+            ctx,
+            src,
+            Some(info.generation),
+            Some(range),
+            info.gzipped,
+            info.key.as_ref(),
+        )?,
+    )
+    .await?;
+
+    let mut dst_file = fs::OpenOptions::new()
+        .write(true)
+        .open(dst)
+        .context("destination path")?;
+    dst_file.seek(std::io::SeekFrom::Start(start))?;
+
+    let mut writer = hash::HashWriter::new(dst_file, false);
+    let written = response.copy_to(&mut writer).await?;
+
+    anyhow::ensure!(
+        written == expected_len,
+        "received {written} bytes, but the slice is {expected_len} bytes"
+    );
+
+    Ok(writer.into_hasher())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn partials_of_other_destinations_are_kept() {
+        for kind in [None, Some("sliced"), Some("gunzip")] {
+            assert!(is_partial_of(
+                &partial_name("app.log", 123, kind),
+                "app.log"
+            ));
+        }
+
+        // The partial of `app.log.1`, which merely starts with `.app.log.`
+        assert!(!is_partial_of(
+            &partial_name("app.log.1", 123, None),
+            "app.log"
+        ));
+        assert!(!is_partial_of(
+            &partial_name("app.log.1", 123, Some("sliced")),
+            "app.log"
+        ));
+        assert!(is_partial_of(
+            &partial_name("app.log.1", 123, None),
+            "app.log.1"
+        ));
+
+        assert!(!is_partial_of(".app.log.123.other.gstmp", "app.log"));
+        assert!(!is_partial_of(".app.log.123", "app.log"));
+        assert!(!is_partial_of(".app.log..gstmp", "app.log"));
+        assert!(!is_partial_of("app.log.123.gstmp", "app.log"));
+    }
+
+    #[test]
+    fn destinations_stay_within_the_directory() {
+        let dst = DataPath::Local(PathBuf::from("out"));
+
+        assert!(matches!(
+            destination(&dst, Some("a/b.txt")).unwrap(),
+            Location::Local(path) if path == Path::new("out/a/b.txt")
+        ));
+
+        for relative in [
+            "../b.txt",
+            "a/../../b.txt",
+            "a/./b.txt",
+            "a//b.txt",
+            "/b.txt",
+            "a/",
+            "..",
+        ] {
+            assert!(
+                destination(&dst, Some(relative)).is_err(),
+                "'{relative}' was mapped to a local path"
+            );
+        }
+    }
+
+    #[test]
+    fn metadata_headers() {
+        assert!(matches!(
+            " cache-CONTROL : no-cache ".parse(),
+            Ok(MetadataHeader::CacheControl(value)) if value == "no-cache"
+        ));
+        assert!(matches!(
+            "Content-Type:text/plain; charset=utf-8".parse(),
+            Ok(MetadataHeader::ContentType(value)) if value == "text/plain; charset=utf-8"
+        ));
+        // The value can contain colons, and custom keys keep their case
+        assert!(matches!(
+            "X-Goog-Meta-Build-Time:12:30".parse(),
+            Ok(MetadataHeader::Custom(key, value)) if key == "Build-Time" && value == "12:30"
+        ));
+
+        for invalid in ["Cache-Control", "x-goog-meta-:value", "Content-Length:5"] {
+            assert!(
+                invalid.parse::<MetadataHeader>().is_err(),
+                "'{invalid}' was accepted"
+            );
+        }
     }
 }
@@ -0,0 +1,239 @@
+//! Customer-supplied encryption keys (CSEK), AES-256 keys that GCS encrypts
+//! objects with but doesn't store, so every request that reads or writes the
+//! content of such an object has to include the key. Its hashes are also only
+//! included in metadata responses if the key is.
+//! See <https://cloud.google.com/storage/docs/encryption/customer-supplied-keys>
+
+use crate::util;
+use anyhow::Context as _;
+use base64::Engine as _;
+use sha2::Digest as _;
+use std::path::Path;
+use tame_gcs::{http, objects};
+
+/// A customer-supplied encryption key
+#[derive(Clone)]
+pub struct Key {
+    /// The base64 encoded key
+    key: String,
+    /// The base64 encoded SHA256 of the key, which is how GCS identifies the
+    /// key an object is encrypted with
+    sha256: String,
+}
+
+impl Key {
+    /// Parses a base64 encoded AES-256 key
+    pub fn parse(key: &str) -> anyhow::Result<Self> {
+        let b64 = base64::engine::general_purpose::STANDARD;
+        let key = key.trim();
+
+        let raw = b64
+            .decode(key)
+            .context("encryption key is not valid base64")?;
+        anyhow::ensure!(
+            raw.len() == 32,
+            "encryption key is {} bytes, but AES-256 keys are 32 bytes",
+            raw.len()
+        );
+
+        Ok(Self {
+            key: key.to_owned(),
+            sha256: b64.encode(sha2::Sha256::digest(&raw)),
+        })
+    }
+
+    #[inline]
+    pub fn sha256(&self) -> &str {
+        &self.sha256
+    }
+
+    fn insert_headers(&self, headers: &mut http::HeaderMap, prefix: &str) -> anyhow::Result<()> {
+        let mut key = http::HeaderValue::from_str(&self.key)?;
+        key.set_sensitive(true);
+
+        headers.insert(
+            http::HeaderName::try_from(format!("{prefix}-algorithm"))?,
+            http::HeaderValue::from_static("AES256"),
+        );
+        headers.insert(http::HeaderName::try_from(format!("{prefix}-key"))?, key);
+        headers.insert(
+            http::HeaderName::try_from(format!("{prefix}-key-sha256"))?,
+            http::HeaderValue::from_str(&self.sha256)?,
+        );
+
+        Ok(())
+    }
+}
+
+/// Adds the headers for the key that the object being read or written is
+/// encrypted with, if any
+pub fn add_headers(headers: &mut http::HeaderMap, key: Option<&Key>) -> anyhow::Result<()> {
+    match key {
+        Some(key) => key.insert_headers(headers, "x-goog-encryption"),
+        None => Ok(()),
+    }
+}
+
+/// Adds the headers for the key that the source object of a rewrite is
+/// encrypted with, if any
+pub fn add_copy_source_headers(
+    headers: &mut http::HeaderMap,
+    key: Option<&Key>,
+) -> anyhow::Result<()> {
+    match key {
+        Some(key) => key.insert_headers(headers, "x-goog-copy-source-encryption"),
+        None => Ok(()),
+    }
+}
+
+/// The customer-supplied encryption keys available to a command
+#[derive(Clone, Default)]
+pub struct Keys {
+    /// The key that new objects are encrypted with, which is also used to
+    /// decrypt objects
+    encryption: Option<Key>,
+    /// Additional keys that are only used to decrypt objects
+    decryption: Vec<Key>,
+}
+
+impl Keys {
+    /// Gathers the keys from the command line, and from a config file in the
+    /// same format as gsutil's boto config, ie. `encryption_key = <key>` and
+    /// `decryption_key1 = <key>` lines, where any other lines are ignored.
+    /// The encryption key on the command line takes precedence over the one
+    /// in the config file.
+    pub fn load(
+        encryption_key: Option<&str>,
+        decryption_keys: &[String],
+        config: Option<&Path>,
+    ) -> anyhow::Result<Self> {
+        let mut keys = Self {
+            encryption: encryption_key
+                .map(Key::parse)
+                .transpose()
+                .context("invalid --encryption-key")?,
+            decryption: decryption_keys
+                .iter()
+                .map(|key| Key::parse(key))
+                .collect::<anyhow::Result<_>>()
+                .context("invalid --decryption-key")?,
+        };
+
+        let Some(config) = config else {
+            return Ok(keys);
+        };
+
+        let contents = std::fs::read_to_string(config)
+            .with_context(|| format!("failed to read '{}'", config.display()))?;
+
+        for (i, line) in contents.lines().enumerate() {
+            let Some((name, value)) = line.split_once('=') else {
+                continue;
+            };
+
+            let name = name.trim();
+            let invalid = || format!("invalid {name} on line {} of '{}'", i + 1, config.display());
+
+            if name == "encryption_key" {
+                let key = Key::parse(value).with_context(invalid)?;
+                keys.encryption.get_or_insert(key);
+            } else if name.starts_with("decryption_key") {
+                keys.decryption
+                    .push(Key::parse(value).with_context(invalid)?);
+            }
+        }
+
+        Ok(keys)
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.encryption.is_none() && self.decryption.is_empty()
+    }
+
+    /// The key that new objects are encrypted with
+    #[inline]
+    pub fn encryption(&self) -> Option<&Key> {
+        self.encryption.as_ref()
+    }
+
+    /// Finds the key with the specified SHA256
+    pub fn find(&self, sha256: &str) -> Option<&Key> {
+        self.encryption
+            .iter()
+            .chain(&self.decryption)
+            .find(|key| key.sha256 == sha256)
+    }
+}
+
+/// Retrieves the SHA256 of the customer-supplied key an object is encrypted
+/// with, or `None` if it isn't encrypted with one
+pub async fn object_key_sha256(
+    ctx: &util::RequestContext,
+    url: &util::GsUrl,
+    generation: Option<i64>,
+) -> anyhow::Result<Option<String>> {
+    let get_req = ctx.obj.get(
+        &(
+            url.bucket(),
+            url.object().context("invalid object name specified")?,
+        ),
+        Some(objects::GetObjectOptional {
+            generation,
+            // tame-gcs' Metadata doesn't have the customerEncryption field
+            standard_params: tame_gcs::common::StandardQueryParameters {
+                fields: Some("customerEncryption"),
+                ..Default::default()
+            },
+            ..Default::default()
+        }),
+    )?;
+
+    let util::JsonResponse(md) = util::execute(ctx, get_req).await?;
+    Ok(md["customerEncryption"]["keySha256"]
+        .as_str()
+        .map(str::to_owned))
+}
+
+/// Finds the supplied key that an object is encrypted with, if any. Unlike
+/// [`key_for`], an object encrypted with a key that wasn't supplied isn't an
+/// error, for requests that work without the key, but include more of the
+/// object's metadata in their response with it.
+pub async fn supplied_key_for(
+    ctx: &util::RequestContext,
+    url: &util::GsUrl,
+    generation: Option<i64>,
+) -> anyhow::Result<Option<Key>> {
+    if ctx.encryption_keys.is_empty() {
+        return Ok(None);
+    }
+
+    Ok(object_key_sha256(ctx, url, generation)
+        .await?
+        .and_then(|sha256| ctx.encryption_keys.find(&sha256).cloned()))
+}
+
+/// Determines the key that is needed to read an object, if any. Objects are
+/// only checked if keys were actually supplied, otherwise GCS will reject
+/// any request for an encrypted object's content with its own error.
+pub async fn key_for(
+    ctx: &util::RequestContext,
+    url: &util::GsUrl,
+    generation: Option<i64>,
+) -> anyhow::Result<Option<Key>> {
+    if ctx.encryption_keys.is_empty() {
+        return Ok(None);
+    }
+
+    let Some(sha256) = object_key_sha256(ctx, url, generation).await? else {
+        return Ok(None);
+    };
+
+    ctx.encryption_keys
+        .find(&sha256)
+        .cloned()
+        .with_context(|| {
+            format!("'{url}' is encrypted with a customer-supplied key whose SHA256 is '{sha256}', but no such key was supplied")
+        })
+        .map(Some)
+}
@@ -0,0 +1,249 @@
+//! Hashing of object content, in the same form as the `crc32c` and `md5Hash`
+//! fields of object metadata, so that local data can be compared against
+//! objects without downloading them
+
+use anyhow::Context as _;
+use base64::Engine as _;
+use md5::Digest as _;
+use std::{io::Write, path::Path};
+
+/// The hashes of some content, base64 encoded as they are in object metadata
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Hashes {
+    pub crc32c: String,
+    /// The md5 is only calculated if requested, as GCS doesn't provide one for
+    /// composite objects, so crc32c is the only hash that is always available
+    pub md5: Option<String>,
+}
+
+impl Hashes {
+    /// Compares the hashes against the ones GCS has for an object, using the
+    /// crc32c if the object has one, or else the md5. Returns `None` if there
+    /// aren't any hashes that can be compared.
+    pub fn matches(&self, expected: &ObjectHashes) -> Option<bool> {
+        if let Some(crc32c) = &expected.crc32c {
+            return Some(crc32c == &self.crc32c);
+        }
+
+        Some(expected.md5.as_ref()? == self.md5.as_ref()?)
+    }
+
+    /// Ensures the hashes match the ones GCS has for an object, comparing
+    /// every hash that is available on both sides. It isn't an error if there
+    /// are no hashes to compare.
+    pub fn verify(&self, expected: &ObjectHashes) -> anyhow::Result<()> {
+        if let Some(crc32c) = &expected.crc32c {
+            anyhow::ensure!(
+                crc32c == &self.crc32c,
+                "crc32c mismatch, expected '{crc32c}' but calculated '{}'",
+                self.crc32c
+            );
+        }
+
+        if let (Some(expected), Some(md5)) = (&expected.md5, &self.md5) {
+            anyhow::ensure!(
+                expected == md5,
+                "md5 mismatch, expected '{expected}' but calculated '{md5}'"
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Formats the hashes as the value of an `x-goog-hash` header
+    pub fn header_value(&self) -> String {
+        match &self.md5 {
+            Some(md5) => format!("crc32c={},md5={md5}", self.crc32c),
+            None => format!("crc32c={}", self.crc32c),
+        }
+    }
+}
+
+/// The hashes GCS has for an object, either of which may be missing
+#[derive(Default, Debug)]
+pub struct ObjectHashes {
+    pub crc32c: Option<String>,
+    pub md5: Option<String>,
+}
+
+impl ObjectHashes {
+    pub fn from_metadata(md: &tame_gcs::objects::Metadata) -> Self {
+        Self {
+            crc32c: md.crc32c.clone(),
+            md5: md.md5_hash.clone(),
+        }
+    }
+
+    /// Parses the `x-goog-hash` headers of a download, which GCS sends as
+    /// either a single comma separated header or as one header per hash
+    pub fn from_headers(headers: &tame_gcs::http::HeaderMap) -> Self {
+        let mut hashes = Self::default();
+
+        for value in headers.get_all("x-goog-hash") {
+            let Ok(value) = value.to_str() else {
+                continue;
+            };
+
+            for hash in value.split(',') {
+                match hash.trim().split_once('=') {
+                    Some(("crc32c", crc32c)) => hashes.crc32c = Some(crc32c.to_owned()),
+                    Some(("md5", md5)) => hashes.md5 = Some(md5.to_owned()),
+                    _ => {}
+                }
+            }
+        }
+
+        hashes
+    }
+}
+
+/// Incrementally hashes content
+#[derive(Clone)]
+pub struct Hasher {
+    crc32c: u32,
+    md5: Option<md5::Md5>,
+}
+
+impl Hasher {
+    pub fn new(md5: bool) -> Self {
+        Self {
+            crc32c: 0,
+            md5: md5.then(md5::Md5::new),
+        }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        self.crc32c = crc32c::crc32c_append(self.crc32c, data);
+        if let Some(md5) = &mut self.md5 {
+            md5.update(data);
+        }
+    }
+
+    /// Appends the hash of content that directly follows the content hashed
+    /// so far, but which was hashed separately, eg. concurrently. Only the
+    /// crc32c can be combined like this, so the md5 is discarded.
+    pub fn combine(self, next: Hasher, next_len: u64) -> Hasher {
+        Self {
+            crc32c: crc32c::crc32c_combine(self.crc32c, next.crc32c, next_len as usize),
+            md5: None,
+        }
+    }
+
+    pub fn finish(self) -> Hashes {
+        let b64 = base64::engine::general_purpose::STANDARD;
+
+        Hashes {
+            // GCS encodes the checksum in big-endian byte order
+            crc32c: b64.encode(self.crc32c.to_be_bytes()),
+            md5: self.md5.map(|md5| b64.encode(md5.finalize())),
+        }
+    }
+}
+
+impl Write for Hasher {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.update(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// A writer that hashes everything written to it before passing it along
+pub struct HashWriter<W> {
+    inner: W,
+    hasher: Hasher,
+}
+
+impl<W: Write> HashWriter<W> {
+    pub fn new(inner: W, md5: bool) -> Self {
+        Self {
+            inner,
+            hasher: Hasher::new(md5),
+        }
+    }
+
+    /// Creates a writer that continues hashing with an existing hasher
+    pub fn with_hasher(inner: W, hasher: Hasher) -> Self {
+        Self { inner, hasher }
+    }
+
+    /// Finishes hashing everything that was written
+    pub fn finish(self) -> Hashes {
+        self.hasher.finish()
+    }
+
+    #[inline]
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.inner
+    }
+
+    #[inline]
+    pub fn into_hasher(self) -> Hasher {
+        self.hasher
+    }
+}
+
+impl<W: Write> Write for HashWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.hasher.update(&buf[..written]);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Hashes the contents of a local file, this reads the entire file so should
+/// be called from a blocking thread
+pub fn hash_file(path: &Path, md5: bool) -> anyhow::Result<Hashes> {
+    let mut file = std::fs::File::open(path)
+        .with_context(|| format!("failed to open '{}'", path.display()))?;
+
+    let mut hasher = Hasher::new(md5);
+    std::io::copy(&mut file, &mut hasher)
+        .with_context(|| format!("failed to read '{}'", path.display()))?;
+
+    Ok(hasher.finish())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn combined_slices_match_the_whole() {
+        let content: Vec<u8> = (0..10_000u32).map(|i| (i * 31 % 251) as u8).collect();
+
+        let mut whole = Hasher::new(false);
+        whole.update(&content);
+        let whole = whole.finish();
+
+        let path = std::env::temp_dir().join(format!("gsutil-hash-test-{}", std::process::id()));
+        std::fs::write(&path, &content).unwrap();
+        let file = hash_file(&path, false);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(file.unwrap(), whole);
+
+        // Uneven slices, including an empty one
+        let mut combined: Option<Hasher> = None;
+        let mut start = 0;
+        for len in [1, 4095, 0, 3000, 2904] {
+            let mut slice = Hasher::new(false);
+            slice.update(&content[start..start + len]);
+            start += len;
+
+            combined = Some(match combined {
+                Some(combined) => combined.combine(slice, len as u64),
+                None => slice,
+            });
+        }
+        assert_eq!(start, content.len());
+
+        assert_eq!(combined.unwrap().finish(), whole);
+    }
+}
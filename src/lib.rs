@@ -2,10 +2,22 @@
 
 pub mod cat;
 pub mod color;
+pub mod compose;
 pub mod cp;
+pub mod encryption;
+pub mod hash;
 pub mod ls;
+pub mod mime;
+pub mod mv;
+pub mod posix;
+pub mod progress;
+pub mod ratelimit;
+pub mod resumable;
 pub mod rm;
+pub mod rsync;
 pub mod setmeta;
 pub mod signurl;
 pub mod stat;
 pub mod util;
+pub mod walk;
+pub mod wildcard;
@@ -1,9 +1,6 @@
-use crate::{color::ColorCtx, util};
+use crate::{color::ColorCtx, util, wildcard};
 use nu_ansi_term::Color;
-use tame_gcs::{
-    common::StandardQueryParameters,
-    objects::{ListOptional, ListResponse, Metadata},
-};
+use tame_gcs::objects::Metadata;
 
 #[derive(clap::Parser, Debug)]
 pub struct Args {
@@ -15,95 +12,92 @@ pub struct Args {
     /// Displays extended metadata as a table
     #[structopt(short, long)]
     long: bool,
-    /// The gs:// url list out
-    url: url::Url,
+    /// The gs:// url list out, which may contain wildcards to list every
+    /// object that matches
+    url: String,
 }
 
 /// Does an ls of a gs bucket minus the prefix specified by the user, this
 /// tries to mimic [exa](https://github.com/ogham/exa) when it can. Would also
 /// be good to support <https://github.com/ogham/exa/blob/master/src/info/filetype.rs> at some point
 pub async fn cmd(ctx: &util::RequestContext, args: Args) -> anyhow::Result<()> {
-    let oid = util::gs_url_to_object_id(&args.url)?;
+    let oid = wildcard::GsPattern::parse(&args.url)?;
 
-    let delimiter = if args.recurse { None } else { Some("/") };
-    let mut prefix = oid.object().map_or("", |on| on.as_ref()).to_owned();
+    if oid.has_wildcard() {
+        return list_matches(ctx, &oid, args.long).await;
+    }
+
+    let mut prefix = oid.pattern().to_owned();
     if !prefix.is_empty() && !prefix.ends_with('/') {
         prefix.push('/');
     }
 
-    let prefix_len = prefix.len();
-    let prefix = Some(prefix);
-
-    let display = if args.long {
-        Display::Long
+    let (display, fields) = if args.long {
+        (Display::Long, "name, updated, size")
     } else {
-        Display::Normal
+        (Display::Normal, "name")
     };
 
     let cc = ColorCtx::from_env();
+    let prefix_len = prefix.len();
+
+    if args.recurse {
+        let objects = util::list_objects(ctx, oid.bucket(), &prefix, None, Some(fields)).await?;
 
-    let mut recurse = if args.recurse {
-        Some(RecursePrinter {
+        let mut recurse = RecursePrinter {
             cc,
             display,
             prefix_len,
             items: Vec::new(),
             current_year: time::OffsetDateTime::now_utc().year(),
-        })
+        };
+
+        recurse.append(objects);
+        recurse.print();
     } else {
-        None
-    };
+        let (objects, prefixes) =
+            util::list_objects_delimited(ctx, oid.bucket(), &prefix, "/", Some(fields)).await?;
 
-    let normal = if !args.recurse {
-        Some(NormalPrinter {
+        NormalPrinter {
             cc,
             display,
             prefix_len,
-        })
+        }
+        .print(objects, prefixes);
+    }
+
+    Ok(())
+}
+
+/// Lists every object matching a wildcard pattern. As the matches can be
+/// spread across any number of "directories", they are always displayed
+/// recursively, relative to the last directory before the first wildcard.
+async fn list_matches(
+    ctx: &util::RequestContext,
+    pattern: &wildcard::GsPattern,
+    long: bool,
+) -> anyhow::Result<()> {
+    let (display, fields) = if long {
+        (Display::Long, "name, updated, size")
     } else {
-        None
+        (Display::Normal, "name")
     };
 
-    let fields = match display {
-        Display::Normal => "items(name), prefixes, nextPageToken",
-        Display::Long => "items(name, updated, size), prefixes, nextPageToken",
-    };
+    let objects = pattern.matches(ctx, Some(fields)).await?;
 
-    let mut page_token: Option<String> = None;
-    loop {
-        let ls_req = ctx.obj.list(
-            oid.bucket(),
-            Some(ListOptional {
-                delimiter,
-                page_token: page_token.as_ref().map(|pt| pt.as_ref()),
-                prefix: prefix.as_ref().map(|s| s.as_ref()),
-                standard_params: StandardQueryParameters {
-                    fields: Some(fields),
-                    ..Default::default()
-                },
-                ..Default::default()
-            }),
-        )?;
-
-        let ls_res: ListResponse = util::execute(ctx, ls_req).await?;
-
-        if let Some(ref np) = normal {
-            np.print(ls_res.objects, ls_res.prefixes);
-        } else if let Some(ref mut rec) = recurse {
-            rec.append(ls_res.objects);
-        }
+    let literal = pattern.literal_prefix();
+    let prefix_len = literal.rfind('/').map_or(0, |i| i + 1);
 
-        // If we have a page token it means there may be more items
-        // that fulfill the parameters
-        page_token = ls_res.page_token;
-        if page_token.is_none() {
-            break;
-        }
-    }
+    let mut recurse = RecursePrinter {
+        cc: ColorCtx::from_env(),
+        display,
+        prefix_len,
+        items: Vec::new(),
+        current_year: time::OffsetDateTime::now_utc().year(),
+    };
 
-    if let Some(ref rec) = recurse {
-        rec.print();
-    }
+    recurse.append(objects);
+    recurse.print();
 
     Ok(())
 }
@@ -4,14 +4,25 @@ enum Command {
     #[clap(name = "cat")]
     Cat(gsutil::cat::Args),
     /// Copy files and objects
+    ///
+    /// Downloads are written to a temporary `.<name>.<generation>.gstmp` file
+    /// next to the destination, which is renamed into place once it has been
+    /// verified. An interrupted download is continued by running the same
+    /// command again, unless the object has changed in the meantime.
     #[clap(name = "cp")]
     Cp(gsutil::cp::Args),
     /// List objects
     #[clap(name = "ls")]
     Ls(gsutil::ls::Args),
+    /// Move files and objects
+    #[clap(name = "mv")]
+    Mv(gsutil::mv::Args),
     /// Remove objects
     #[clap(name = "rm")]
     Rm(gsutil::rm::Args),
+    /// Synchronize the contents of a directory and a bucket prefix
+    #[clap(name = "rsync")]
+    Rsync(gsutil::rsync::Args),
     /// Set metadata on objects
     #[clap(name = "setmeta")]
     SetMeta(gsutil::setmeta::Args),
@@ -29,15 +40,80 @@ struct Opts {
     /// Path to a service account credentials file used to obtain oauth2 tokens.
     #[clap(short, long, env = "GOOGLE_APPLICATION_CREDENTIALS")]
     credentials: Option<std::path::PathBuf>,
+    /// The maximum number of requests to run concurrently when a command
+    /// operates on multiple files or objects. `-m` on its own uses the number
+    /// of available CPUs, the same as gsutil's `-m` flag.
+    #[clap(
+        short = 'm',
+        long,
+        default_value = "1",
+        num_args = 0..=1,
+        default_missing_value = default_jobs()
+    )]
+    jobs: std::num::NonZeroUsize,
+    /// A base64 encoded AES-256 customer-supplied encryption key, which new
+    /// objects are encrypted with, and which is used to read objects that
+    /// are encrypted with it
+    #[clap(long, env = "GSUTIL_ENCRYPTION_KEY", hide_env_values = true)]
+    encryption_key: Option<String>,
+    /// Additional base64 encoded AES-256 customer-supplied encryption keys
+    /// that are only used to read objects encrypted with them. Can be
+    /// repeated, or comma separated in the environment variable.
+    #[clap(
+        long,
+        env = "GSUTIL_DECRYPTION_KEYS",
+        hide_env_values = true,
+        value_delimiter = ','
+    )]
+    decryption_key: Vec<String>,
+    /// A config file containing customer-supplied encryption keys, in the
+    /// same format as gsutil's boto config, ie. `encryption_key = <key>` and
+    /// `decryption_key1 = <key>` lines
+    #[clap(long, env = "GSUTIL_ENCRYPTION_CONFIG")]
+    encryption_config: Option<std::path::PathBuf>,
+    /// Limit the combined rate that data is uploaded and downloaded at by all
+    /// concurrent requests, in bytes per second, with an optional binary
+    /// suffix, eg. `20M`
+    #[clap(long, value_name = "RATE", env = "GSUTIL_LIMIT_RATE")]
+    limit_rate: Option<gsutil::util::ByteSize>,
     #[clap(subcommand)]
     cmd: Command,
 }
 
+/// The number of jobs used when `-m` is passed without a value
+fn default_jobs() -> &'static str {
+    static JOBS: std::sync::OnceLock<String> = std::sync::OnceLock::new();
+
+    JOBS.get_or_init(|| {
+        std::thread::available_parallelism()
+            .map_or(1, std::num::NonZeroUsize::get)
+            .to_string()
+    })
+}
+
+/// Gets the command line arguments. `-m` is valid without a value, but clap
+/// would take the subcommand that follows it as its value, eg. in
+/// `gsutil -m cp ...`, so the default value is inserted explicitly.
+fn args() -> Vec<std::ffi::OsString> {
+    use clap::CommandFactory as _;
+
+    let cmd = Opts::command();
+    let mut args: Vec<_> = std::env::args_os().collect();
+
+    if let Some(i) = (1..args.len()).find(|&i| cmd.find_subcommand(&args[i]).is_some()) {
+        if matches!(args[i - 1].to_str(), Some("-m" | "--jobs")) {
+            args.insert(i, default_jobs().into());
+        }
+    }
+
+    args
+}
+
 async fn real_main() -> anyhow::Result<()> {
     use anyhow::Context as _;
     use clap::Parser;
 
-    let args = Opts::parse();
+    let args = Opts::parse_from(args());
 
     let client = reqwest::Client::builder().build()?;
 
@@ -62,13 +138,28 @@ async fn real_main() -> anyhow::Result<()> {
         client,
         auth: std::sync::Arc::new(token_provider),
         obj: tame_gcs::objects::Object::default(),
+        jobs: args.jobs.get(),
+        encryption_keys: gsutil::encryption::Keys::load(
+            args.encryption_key.as_deref(),
+            &args.decryption_key,
+            args.encryption_config.as_deref(),
+        )?,
+        progress: Default::default(),
+        rate_limit: match args.limit_rate {
+            Some(rate) => {
+                gsutil::ratelimit::RateLimit::new(rate.0).context("invalid --limit-rate")?
+            }
+            None => Default::default(),
+        },
     };
 
     match args.cmd {
         Command::Cat(args) => gsutil::cat::cmd(&ctx, args).await,
         Command::Cp(args) => gsutil::cp::cmd(&ctx, args).await,
         Command::Ls(args) => gsutil::ls::cmd(&ctx, args).await,
+        Command::Mv(args) => gsutil::mv::cmd(&ctx, args).await,
         Command::Rm(args) => gsutil::rm::cmd(&ctx, args).await,
+        Command::Rsync(args) => gsutil::rsync::cmd(&ctx, args).await,
         Command::SetMeta(args) => gsutil::setmeta::cmd(&ctx, args).await,
         Command::Signurl(sargs) => {
             use anyhow::Context as _;
@@ -0,0 +1,221 @@
+//! Detection of the Content-Type of uploaded files, either from their
+//! extension or, optionally, from the magic bytes at the start of their
+//! content
+
+use std::{io::Read, path::Path};
+
+/// The number of bytes at the start of a file that are read when sniffing
+/// its content, which is enough to reach the tar header magic
+const SNIFF_LEN: usize = 512;
+
+/// Determines the Content-Type of a file from its extension
+pub fn from_path(path: &Path) -> Option<&'static str> {
+    from_extension(path.extension()?.to_str()?)
+}
+
+/// Determines the Content-Type for a file extension, ignoring case
+pub fn from_extension(ext: &str) -> Option<&'static str> {
+    let mt = match ext.to_ascii_lowercase().as_str() {
+        // Text
+        "css" => "text/css",
+        "csv" => "text/csv",
+        "htm" | "html" => "text/html",
+        "ics" => "text/calendar",
+        "js" | "mjs" => "text/javascript",
+        "md" | "markdown" => "text/markdown",
+        "txt" | "text" | "log" | "conf" | "ini" => "text/plain",
+        "tsv" => "text/tab-separated-values",
+        "xml" => "text/xml",
+        "yaml" | "yml" => "application/yaml",
+        "toml" => "application/toml",
+        // Application
+        "json" | "map" => "application/json",
+        "jsonld" => "application/ld+json",
+        "webmanifest" => "application/manifest+json",
+        "wasm" => "application/wasm",
+        "pdf" => "application/pdf",
+        "rtf" => "application/rtf",
+        "xhtml" => "application/xhtml+xml",
+        "atom" => "application/atom+xml",
+        "rss" => "application/rss+xml",
+        "zip" => "application/zip",
+        "gz" | "tgz" => "application/gzip",
+        "bz2" => "application/x-bzip2",
+        "xz" => "application/x-xz",
+        "zst" => "application/zstd",
+        "7z" => "application/x-7z-compressed",
+        "tar" => "application/x-tar",
+        "rar" => "application/vnd.rar",
+        "jar" => "application/java-archive",
+        "apk" => "application/vnd.android.package-archive",
+        "doc" => "application/msword",
+        "docx" => "application/vnd.openxmlformats-officedocument.wordprocessingml.document",
+        "xls" => "application/vnd.ms-excel",
+        "xlsx" => "application/vnd.openxmlformats-officedocument.spreadsheetml.sheet",
+        "ppt" => "application/vnd.ms-powerpoint",
+        "pptx" => "application/vnd.openxmlformats-officedocument.presentationml.presentation",
+        "sh" => "application/x-sh",
+        // Images
+        "avif" => "image/avif",
+        "bmp" => "image/bmp",
+        "dds" => "image/vnd.ms-dds",
+        "gif" => "image/gif",
+        "ico" => "image/vnd.microsoft.icon",
+        "jpg" | "jpeg" => "image/jpeg",
+        "ktx" => "image/ktx",
+        "ktx2" => "image/ktx2",
+        "png" => "image/png",
+        "svg" => "image/svg+xml",
+        "tif" | "tiff" => "image/tiff",
+        "webp" => "image/webp",
+        // Audio and video
+        "aac" => "audio/aac",
+        "flac" => "audio/flac",
+        "m4a" => "audio/mp4",
+        "mid" | "midi" => "audio/midi",
+        "mp3" => "audio/mpeg",
+        "oga" | "ogg" | "opus" => "audio/ogg",
+        "wav" => "audio/wav",
+        "weba" => "audio/webm",
+        "avi" => "video/x-msvideo",
+        "m3u8" => "application/vnd.apple.mpegurl",
+        "mkv" => "video/x-matroska",
+        "mov" => "video/quicktime",
+        "mp4" | "m4v" => "video/mp4",
+        "mpeg" | "mpg" => "video/mpeg",
+        "ogv" => "video/ogg",
+        "ts" => "video/mp2t",
+        "webm" => "video/webm",
+        // Fonts
+        "otf" => "font/otf",
+        "ttf" => "font/ttf",
+        "woff" => "font/woff",
+        "woff2" => "font/woff2",
+        // 3D models
+        "glb" => "model/gltf-binary",
+        "gltf" => "model/gltf+json",
+        "obj" => "model/obj",
+        "stl" => "model/stl",
+        _ => return None,
+    };
+
+    Some(mt)
+}
+
+/// Determines the Content-Type of some content from the magic bytes at its
+/// start. Content that doesn't match any known signature, but is valid UTF-8
+/// without any control characters, is considered plain text.
+pub fn sniff(content: &[u8]) -> Option<&'static str> {
+    const SIGNATURES: &[(usize, &[u8], &str)] = &[
+        (0, b"\x89PNG\r\n\x1a\n", "image/png"),
+        (0, b"\xff\xd8\xff", "image/jpeg"),
+        (0, b"GIF87a", "image/gif"),
+        (0, b"GIF89a", "image/gif"),
+        (8, b"WEBP", "image/webp"),
+        (0, b"II*\0", "image/tiff"),
+        (0, b"MM\0*", "image/tiff"),
+        (0, b"\0\0\x01\0", "image/vnd.microsoft.icon"),
+        (0, b"DDS ", "image/vnd.ms-dds"),
+        (0, b"\xabKTX 11\xbb", "image/ktx"),
+        (0, b"\xabKTX 20\xbb", "image/ktx2"),
+        (0, b"%PDF-", "application/pdf"),
+        (0, b"PK\x03\x04", "application/zip"),
+        (0, b"\x1f\x8b", "application/gzip"),
+        (0, b"BZh", "application/x-bzip2"),
+        (0, b"\xfd7zXZ\0", "application/x-xz"),
+        (0, b"\x28\xb5\x2f\xfd", "application/zstd"),
+        (0, b"7z\xbc\xaf\x27\x1c", "application/x-7z-compressed"),
+        (257, b"ustar", "application/x-tar"),
+        (0, b"\0asm", "application/wasm"),
+        (0, b"glTF", "model/gltf-binary"),
+        (0, b"wOFF", "font/woff"),
+        (0, b"wOF2", "font/woff2"),
+        (0, b"OTTO", "font/otf"),
+        (0, b"\0\x01\0\0", "font/ttf"),
+        (0, b"OggS", "audio/ogg"),
+        (0, b"fLaC", "audio/flac"),
+        (0, b"ID3", "audio/mpeg"),
+        (8, b"WAVE", "audio/wav"),
+        (8, b"AVI ", "video/x-msvideo"),
+        (4, b"ftyp", "video/mp4"),
+        (0, b"\x1a\x45\xdf\xa3", "video/webm"),
+    ];
+
+    let signature = SIGNATURES.iter().find(|(offset, magic, _)| {
+        content
+            .get(*offset..offset + magic.len())
+            .is_some_and(|bytes| bytes == *magic)
+    });
+
+    if let Some((_, _, mt)) = signature {
+        return Some(mt);
+    }
+
+    if is_bmp(content) {
+        return Some("image/bmp");
+    }
+
+    // The content may have been cut off in the middle of a character
+    let text = match std::str::from_utf8(content) {
+        Ok(text) => text,
+        Err(err) if err.error_len().is_none() => {
+            std::str::from_utf8(&content[..err.valid_up_to()]).ok()?
+        }
+        Err(_) => return None,
+    };
+
+    (!text.is_empty()
+        && !text
+            .chars()
+            .any(|c| c.is_control() && !c.is_ascii_whitespace()))
+    .then_some("text/plain")
+}
+
+/// Determines if the content is a bitmap. Its `BM` signature is so short
+/// that plenty of text starts with it, so the header fields that are fixed
+/// in every bitmap are checked as well, ie. the reserved field after the file
+/// size is zero, and the DIB header that follows is one of the known sizes.
+fn is_bmp(content: &[u8]) -> bool {
+    let (Some(signature), Some(reserved), Some(dib_size)) =
+        (content.get(..2), content.get(6..10), content.get(14..18))
+    else {
+        return false;
+    };
+
+    let dib_size = u32::from_le_bytes([dib_size[0], dib_size[1], dib_size[2], dib_size[3]]);
+
+    signature == b"BM"
+        && reserved == [0; 4]
+        && matches!(dib_size, 12 | 16 | 40 | 52 | 56 | 64 | 108 | 124)
+}
+
+/// Determines the Content-Type of a file by sniffing the start of its
+/// content, see [`sniff`]
+pub fn sniff_file(path: &Path) -> std::io::Result<Option<&'static str>> {
+    let mut start = Vec::with_capacity(SNIFF_LEN);
+    std::fs::File::open(path)?
+        .take(SNIFF_LEN as u64)
+        .read_to_end(&mut start)?;
+
+    Ok(sniff(&start))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn bmp_requires_a_valid_header() {
+        let mut bmp = b"BM\x46\0\0\0\0\0\0\0\x36\0\0\0\x28\0\0\0".to_vec();
+        bmp.resize(70, 0);
+        assert_eq!(sniff(&bmp), Some("image/bmp"));
+
+        assert_eq!(sniff(b"BMW 3 series, 2004, 120000km"), Some("text/plain"));
+    }
+
+    #[test]
+    fn dds() {
+        assert_eq!(from_extension("dds"), Some("image/vnd.ms-dds"));
+        assert_eq!(sniff(b"DDS \x7c\0\0\0"), Some("image/vnd.ms-dds"));
+    }
+}
@@ -0,0 +1,208 @@
+use crate::{
+    cp::{self, Location},
+    encryption, progress, util,
+};
+use anyhow::Context as _;
+use tame_gcs::objects;
+
+#[derive(clap::Parser)]
+#[group(skip)]
+pub struct Args {
+    #[clap(flatten)]
+    cp: cp::Args,
+}
+
+/// Moves files and objects by copying them with the same code paths as `cp`,
+/// then removing each source once its copy has been verified
+pub async fn cmd(ctx: &util::RequestContext, args: Args) -> anyhow::Result<()> {
+    let opts = &args.cp.opts;
+    let conditions = &args.cp.conditions;
+
+    let moves = cp::plan(ctx, &args.cp).await?;
+    let reporter = cp::start_progress(ctx, &args.cp, &moves).await;
+
+    let ctx = &util::RequestContext {
+        progress: reporter
+            .as_ref()
+            .map(progress::Reporter::progress)
+            .unwrap_or_default(),
+        ..ctx.clone()
+    };
+
+    let res =
+        util::for_each_concurrent(ctx, moves, |cp::PlannedCopy { src, dst, .. }| async move {
+            let res = mv(ctx, opts, conditions, &src, &dst)
+                .await
+                .with_context(|| format!("failed to move '{src}' to '{dst}'"));
+            ctx.progress.object_done();
+            res
+        })
+        .await;
+
+    if let Some(reporter) = reporter {
+        reporter.finish().await;
+    }
+
+    res
+}
+
+/// Moves a single file or object. If the copy is skipped because of `-n`, the
+/// source is kept.
+async fn mv(
+    ctx: &util::RequestContext,
+    args: &cp::Options,
+    conditions: &cp::Preconditions,
+    src: &Location,
+    dst: &Location,
+) -> anyhow::Result<()> {
+    let destination = dst;
+
+    match (src, dst) {
+        (Location::Local(src), Location::Gs(dst)) => {
+            let src_len = std::fs::metadata(src)?.len();
+            let Some(uploaded) = copied(
+                conditions,
+                destination,
+                cp::upload(ctx, args, conditions, src, dst).await,
+            )?
+            else {
+                return Ok(());
+            };
+
+            // Compressed uploads are verified against the hashes of the
+            // compressed content instead
+            anyhow::ensure!(
+                args.gzips(src) || uploaded.size == Some(src_len),
+                "uploaded object is {:?} bytes, but the source is {src_len} bytes",
+                uploaded.size
+            );
+
+            std::fs::remove_file(src).context("failed to remove source")?;
+        }
+        (Location::Gs(_), Location::Local(dst)) if conditions.skips_file(dst) => {
+            cp::report_skipped(destination);
+        }
+        (Location::Gs(src), Location::Local(dst)) => {
+            let src_md = get(ctx, src).await?;
+            let generation = src_md.generation.context("source is missing generation")?;
+
+            let written = cp::download(ctx, args, src, dst, Some(generation)).await?;
+            cp::restore_attributes(args, dst, &src_md)?;
+
+            anyhow::ensure!(
+                src_md.size == Some(written),
+                "downloaded {written} bytes, but the source is {:?} bytes",
+                src_md.size
+            );
+
+            delete(ctx, src, generation).await?;
+        }
+        (Location::Gs(src), Location::Gs(dst)) => {
+            anyhow::ensure!(
+                src.to_string() != dst.to_string(),
+                "source and destination are the same object"
+            );
+
+            let src_md = get(ctx, src).await?;
+            let generation = src_md.generation.context("source is missing generation")?;
+
+            let Some(copied) = copied(
+                conditions,
+                destination,
+                cp::rewrite(ctx, args, conditions, src, dst, Some(generation)).await,
+            )?
+            else {
+                return Ok(());
+            };
+
+            anyhow::ensure!(
+                copied.size == src_md.size && copied.crc32c == src_md.crc32c,
+                "the size or crc32c of the copy doesn't match the source"
+            );
+
+            delete(ctx, src, generation).await?;
+        }
+        (Location::Local(_), Location::Local(_)) => {
+            anyhow::bail!("source and destination are both located on local disk")
+        }
+        (Location::Stdio, _) | (_, Location::Stdio) => {
+            anyhow::bail!("stdin and stdout can't be moved")
+        }
+    }
+
+    Ok(())
+}
+
+/// Reports a copy that was skipped because of `-n` and returns `None`, so
+/// that the source isn't removed
+fn copied<T>(
+    conditions: &cp::Preconditions,
+    dst: &Location,
+    res: anyhow::Result<T>,
+) -> anyhow::Result<Option<T>> {
+    match res {
+        Ok(copied) => Ok(Some(copied)),
+        Err(err) if conditions.is_skipped(&err) => {
+            cp::report_skipped(dst);
+            Ok(None)
+        }
+        Err(err) => Err(err),
+    }
+}
+
+/// Gets the metadata of the source that is needed to verify the copy
+async fn get(ctx: &util::RequestContext, src: &util::GsUrl) -> anyhow::Result<objects::Metadata> {
+    let mut get_req = ctx.obj.get(
+        &(
+            src.bucket(),
+            src.object().context("invalid object name specified")?,
+        ),
+        Some(objects::GetObjectOptional {
+            standard_params: tame_gcs::common::StandardQueryParameters {
+                fields: Some("generation, size, crc32c, metadata"),
+                ..Default::default()
+            },
+            ..Default::default()
+        }),
+    )?;
+
+    // The key is needed for the crc32c
+    let key = encryption::key_for(ctx, src, None).await?;
+    encryption::add_headers(get_req.headers_mut(), key.as_ref())?;
+
+    let get_res: objects::GetObjectResponse = util::execute(ctx, get_req).await?;
+    Ok(get_res.metadata)
+}
+
+/// Deletes the source object, but only if it is still the generation that was
+/// copied, so that if it was overwritten in the meantime the new data isn't lost
+async fn delete(
+    ctx: &util::RequestContext,
+    src: &util::GsUrl,
+    generation: i64,
+) -> anyhow::Result<()> {
+    let del_req = ctx.obj.delete(
+        &(
+            src.bucket(),
+            src.object().context("invalid object name specified")?,
+        ),
+        Some(objects::DeleteObjectOptional {
+            conditionals: tame_gcs::common::Conditionals {
+                if_generation_match: Some(generation),
+                ..Default::default()
+            },
+            ..Default::default()
+        }),
+    )?;
+
+    match util::execute::<_, objects::DeleteObjectResponse>(ctx, del_req).await {
+        Ok(_) => Ok(()),
+        Err(err)
+            if util::error_status(&err)
+                == Some(tame_gcs::http::StatusCode::PRECONDITION_FAILED) =>
+        {
+            Err(err.context("source was modified after it was copied, so it was not removed"))
+        }
+        Err(err) => Err(err),
+    }
+}
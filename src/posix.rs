@@ -0,0 +1,105 @@
+//! POSIX file attributes that are stored in the custom metadata of uploaded
+//! objects, using the same keys as gsutil, so that they can be restored when
+//! the objects are downloaded again
+
+use anyhow::Context as _;
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::Path,
+    time::{Duration, UNIX_EPOCH},
+};
+
+/// The key that the modification time of a file is stored under, as unix
+/// seconds
+pub const MTIME_KEY: &str = "goog-reserved-file-mtime";
+/// The key that the permission bits of a file are stored under, in octal
+pub const MODE_KEY: &str = "goog-reserved-posix-mode";
+/// The key that the user id of the owner of a file is stored under
+pub const UID_KEY: &str = "goog-reserved-posix-uid";
+/// The key that the group id of the owner of a file is stored under
+pub const GID_KEY: &str = "goog-reserved-posix-gid";
+
+/// Gets the mode, uid, and gid of a file as custom metadata. These don't
+/// exist on other platforms, so nothing is returned there.
+pub fn attributes(md: &fs::Metadata) -> Vec<(&'static str, String)> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt as _;
+
+        vec![
+            (MODE_KEY, format!("{:03o}", md.mode() & 0o777)),
+            (UID_KEY, md.uid().to_string()),
+            (GID_KEY, md.gid().to_string()),
+        ]
+    }
+
+    #[cfg(not(unix))]
+    {
+        let _ = md;
+        Vec::new()
+    }
+}
+
+/// Restores the modification time stored in an object's custom metadata to
+/// the file it was downloaded to, if it has one that can be parsed
+pub fn restore_mtime(path: &Path, metadata: &BTreeMap<String, String>) -> anyhow::Result<()> {
+    let Some(mtime) = metadata
+        .get(MTIME_KEY)
+        .and_then(|mtime| mtime.trim().parse::<i64>().ok())
+    else {
+        return Ok(());
+    };
+
+    let offset = Duration::from_secs(mtime.unsigned_abs());
+    let mtime = if mtime >= 0 {
+        UNIX_EPOCH.checked_add(offset)
+    } else {
+        UNIX_EPOCH.checked_sub(offset)
+    };
+
+    if let Some(mtime) = mtime {
+        fs::File::options()
+            .write(true)
+            .open(path)
+            .and_then(|file| file.set_modified(mtime))
+            .context("failed to set the modification time")?;
+    }
+
+    Ok(())
+}
+
+/// Restores the attributes stored in an object's custom metadata to the file
+/// it was downloaded to. Attributes with values that can't be parsed are
+/// ignored, as is the owner if we don't have permission to change it.
+pub fn restore(path: &Path, metadata: &BTreeMap<String, String>) -> anyhow::Result<()> {
+    #[cfg(unix)]
+    let get = |key: &str| metadata.get(key).map(|value| value.trim());
+
+    // The owner is restored first, as changing it can clear the setuid and
+    // setgid bits, and the mode last, as it can make the file read only
+    #[cfg(unix)]
+    {
+        let uid = get(UID_KEY).and_then(|uid| uid.parse().ok());
+        let gid = get(GID_KEY).and_then(|gid| gid.parse().ok());
+
+        if uid.is_some() || gid.is_some() {
+            match std::os::unix::fs::chown(path, uid, gid) {
+                Err(err) if err.kind() == std::io::ErrorKind::PermissionDenied => {}
+                res => res.context("failed to change the owner")?,
+            }
+        }
+    }
+
+    restore_mtime(path, metadata)?;
+
+    #[cfg(unix)]
+    if let Some(mode) = get(MODE_KEY).and_then(|mode| u32::from_str_radix(mode, 8).ok()) {
+        use std::os::unix::fs::PermissionsExt as _;
+
+        fs::set_permissions(path, fs::Permissions::from_mode(mode & 0o7777))
+            .context("failed to set the permissions")?;
+    }
+
+    Ok(())
+}
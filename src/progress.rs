@@ -0,0 +1,289 @@
+//! Progress reporting for transfers. The bytes transferred are counted where
+//! the data is actually read or received, and a [`Reporter`] periodically
+//! prints the totals to stderr, either as a bar that is redrawn in place on a
+//! terminal, or as plain lines that don't clutter logs otherwise.
+
+use crate::color::ColorCtx;
+use nu_ansi_term::Color;
+use std::{
+    io::Write as _,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+/// How often the bar is redrawn on a terminal
+const BAR_INTERVAL: Duration = Duration::from_millis(200);
+/// How often a line is printed when stderr isn't a terminal
+const LINE_INTERVAL: Duration = Duration::from_secs(10);
+/// The number of characters the bar itself is wide
+const BAR_WIDTH: usize = 24;
+
+struct Counters {
+    bytes: AtomicU64,
+    objects: AtomicU64,
+    /// The total number of bytes that will be transferred, if known
+    total_bytes: Option<u64>,
+    total_objects: u64,
+    started: Instant,
+}
+
+/// A handle used to record the progress of a transfer, which does nothing if
+/// progress isn't being reported
+#[derive(Clone, Default)]
+pub struct Progress(Option<Arc<Counters>>);
+
+impl Progress {
+    /// Records that bytes have been transferred
+    #[inline]
+    pub fn add(&self, bytes: u64) {
+        if let Some(counters) = &self.0 {
+            counters.bytes.fetch_add(bytes, Ordering::Relaxed);
+        }
+    }
+
+    /// Records that an object has been transferred, or has failed or been
+    /// skipped, so that it no longer needs to be transferred
+    #[inline]
+    pub fn object_done(&self) {
+        if let Some(counters) = &self.0 {
+            counters.objects.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Wraps a source so that everything read from it is recorded
+    pub fn reader<R>(&self, inner: R) -> Reader<R> {
+        Reader {
+            inner,
+            progress: self.clone(),
+        }
+    }
+}
+
+/// A reader that records the bytes read through it as transferred
+pub struct Reader<R> {
+    inner: R,
+    progress: Progress,
+}
+
+impl<R: std::io::Read> std::io::Read for Reader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.progress.add(read as u64);
+        Ok(read)
+    }
+}
+
+/// A snapshot of the counters
+struct Snapshot {
+    bytes: u64,
+    total_bytes: Option<u64>,
+    objects: u64,
+    total_objects: u64,
+    /// The average number of bytes transferred per second
+    rate: f64,
+}
+
+impl Snapshot {
+    fn new(counters: &Counters) -> Self {
+        let bytes = counters.bytes.load(Ordering::Relaxed);
+        let elapsed = counters.started.elapsed().as_secs_f64();
+
+        Self {
+            bytes,
+            // Data can be counted twice, eg. if a download is restarted, so
+            // don't let that push us past the total
+            total_bytes: counters.total_bytes.map(|total| total.max(bytes)),
+            objects: counters.objects.load(Ordering::Relaxed),
+            total_objects: counters.total_objects,
+            rate: if elapsed > 0.0 {
+                bytes as f64 / elapsed
+            } else {
+                0.0
+            },
+        }
+    }
+
+    /// The fraction of the bytes that have been transferred, if the total is
+    /// known
+    fn fraction(&self) -> Option<f64> {
+        let total = self.total_bytes?;
+        Some(if total == 0 {
+            1.0
+        } else {
+            self.bytes as f64 / total as f64
+        })
+    }
+
+    /// The estimated time until the remaining bytes have been transferred
+    fn eta(&self) -> Option<Duration> {
+        let remaining = self.total_bytes? - self.bytes;
+        if remaining == 0 {
+            return Some(Duration::ZERO);
+        }
+
+        (self.rate >= 1.0).then(|| Duration::from_secs_f64(remaining as f64 / self.rate))
+    }
+
+    /// Formats the counters, without the bar, eg.
+    /// `1.2GB / 2.6GB (45%), 35.1MB/s, ETA 00:41, 3/10 objects`
+    fn describe(&self) -> String {
+        let mut line = format_bytes(self.bytes);
+
+        if let Some(total) = self.total_bytes {
+            line.push_str(" / ");
+            line.push_str(&format_bytes(total));
+        }
+
+        if let Some(fraction) = self.fraction() {
+            line.push_str(&format!(" ({:.0}%)", fraction * 100.0));
+        }
+
+        line.push_str(&format!(", {}/s", format_bytes(self.rate as u64)));
+
+        if let Some(eta) = self.eta() {
+            line.push_str(&format!(", ETA {}", format_duration(eta)));
+        }
+
+        if self.total_objects > 1 {
+            line.push_str(&format!(
+                ", {}/{} objects",
+                self.objects, self.total_objects
+            ));
+        }
+
+        line
+    }
+}
+
+/// Formats a number of bytes the same way as `ls -l`
+fn format_bytes(bytes: u64) -> String {
+    use number_prefix::NumberPrefix;
+
+    match NumberPrefix::decimal(bytes as f64) {
+        NumberPrefix::Standalone(b) => format!("{b}B"),
+        NumberPrefix::Prefixed(p, n) => {
+            if n < 10f64 {
+                format!("{:.1}{}B", n, p.symbol())
+            } else {
+                format!("{:.0}{}B", n, p.symbol())
+            }
+        }
+    }
+}
+
+/// Formats a duration as `MM:SS`, or `H:MM:SS` if it's an hour or longer
+fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    let (hours, mins, secs) = (secs / 3600, secs / 60 % 60, secs % 60);
+
+    if hours > 0 {
+        format!("{hours}:{mins:02}:{secs:02}")
+    } else {
+        format!("{mins:02}:{secs:02}")
+    }
+}
+
+/// Periodically prints the progress of a transfer to stderr until it is
+/// finished
+pub struct Reporter {
+    progress: Progress,
+    task: tokio::task::JoinHandle<()>,
+    /// Set once anything has been printed
+    printed: Arc<std::sync::atomic::AtomicBool>,
+    cc: ColorCtx,
+}
+
+impl Reporter {
+    /// Starts reporting the progress of a transfer of the specified number of
+    /// objects, which total `total_bytes` if all of their sizes are known
+    pub fn start(cc: ColorCtx, total_objects: u64, total_bytes: Option<u64>) -> Self {
+        let counters = Arc::new(Counters {
+            bytes: AtomicU64::new(0),
+            objects: AtomicU64::new(0),
+            total_bytes,
+            total_objects,
+            started: Instant::now(),
+        });
+        let printed = Arc::new(std::sync::atomic::AtomicBool::new(false));
+
+        let task = tokio::spawn({
+            let counters = counters.clone();
+            let printed = printed.clone();
+
+            async move {
+                let interval = if cc.stderr_is_terminal() {
+                    BAR_INTERVAL
+                } else {
+                    LINE_INTERVAL
+                };
+
+                let mut ticks =
+                    tokio::time::interval_at(tokio::time::Instant::now() + interval, interval);
+
+                loop {
+                    ticks.tick().await;
+                    print(&cc, &Snapshot::new(&counters));
+                    printed.store(true, Ordering::Relaxed);
+                }
+            }
+        });
+
+        Self {
+            progress: Progress(Some(counters)),
+            task,
+            printed,
+            cc,
+        }
+    }
+
+    /// The handle that the transfer is recorded with
+    #[inline]
+    pub fn progress(&self) -> Progress {
+        self.progress.clone()
+    }
+
+    /// Stops reporting, printing the final state of the transfer if any
+    /// progress was printed during it, so that quick transfers stay quiet
+    pub async fn finish(self) {
+        self.task.abort();
+        let _ = self.task.await;
+
+        if !self.printed.load(Ordering::Relaxed) {
+            return;
+        }
+
+        if let Some(counters) = &self.progress.0 {
+            print(&self.cc, &Snapshot::new(counters));
+        }
+
+        if self.cc.stderr_is_terminal() {
+            eprintln!();
+        }
+    }
+}
+
+/// Prints the snapshot, redrawing the bar in place on a terminal
+fn print(cc: &ColorCtx, snapshot: &Snapshot) {
+    let mut stderr = std::io::stderr().lock();
+
+    if !cc.stderr_is_terminal() {
+        let _ = writeln!(stderr, "{}", snapshot.describe());
+        return;
+    }
+
+    let filled = snapshot.fraction().map_or(0, |fraction| {
+        (fraction.min(1.0) * BAR_WIDTH as f64) as usize
+    });
+
+    let _ = write!(
+        stderr,
+        "\r\x1b[2K[{}{}] {}",
+        cc.paint_err(Color::Green, "=".repeat(filled)),
+        " ".repeat(BAR_WIDTH - filled),
+        snapshot.describe()
+    );
+    let _ = stderr.flush();
+}
@@ -0,0 +1,73 @@
+//! Bandwidth limiting, shared by every request made by the process so that
+//! concurrent transfers are limited as a whole rather than individually
+
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+struct State {
+    /// The number of bytes that can be transferred immediately, which is
+    /// negative if transfers have borrowed against future tokens
+    tokens: f64,
+    /// When the tokens were last refilled
+    refilled: Instant,
+}
+
+struct Bucket {
+    /// The number of bytes per second that are added to the bucket, which is
+    /// also the most the bucket can hold, so that at most a second's worth of
+    /// data can be sent in a burst after being idle
+    rate: f64,
+    state: Mutex<State>,
+}
+
+/// A token bucket that limits the rate data is transferred at, which doesn't
+/// limit anything by default
+#[derive(Clone, Default)]
+pub struct RateLimit(Option<Arc<Bucket>>);
+
+impl RateLimit {
+    /// Creates a limit of `bytes_per_sec`, which must be greater than 0
+    pub fn new(bytes_per_sec: u64) -> anyhow::Result<Self> {
+        anyhow::ensure!(bytes_per_sec > 0, "the rate limit must be greater than 0");
+
+        let rate = bytes_per_sec as f64;
+        Ok(Self(Some(Arc::new(Bucket {
+            rate,
+            state: Mutex::new(State {
+                tokens: rate,
+                refilled: Instant::now(),
+            }),
+        }))))
+    }
+
+    /// Takes tokens for the specified number of bytes, waiting until the
+    /// bucket has been refilled if they exceed the tokens that are available.
+    /// Any number of bytes can be taken at once, as the bucket can go into
+    /// debt, which later callers then wait for to be repaid.
+    pub async fn acquire(&self, bytes: u64) {
+        let Some(bucket) = &self.0 else {
+            return;
+        };
+
+        let wait = {
+            let mut state = bucket
+                .state
+                .lock()
+                .unwrap_or_else(std::sync::PoisonError::into_inner);
+
+            let now = Instant::now();
+            let elapsed = now.duration_since(state.refilled).as_secs_f64();
+            state.tokens = (state.tokens + elapsed * bucket.rate).min(bucket.rate);
+            state.refilled = now;
+
+            state.tokens -= bytes as f64;
+            (state.tokens < 0.0).then(|| Duration::from_secs_f64(-state.tokens / bucket.rate))
+        };
+
+        if let Some(wait) = wait {
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
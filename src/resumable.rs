@@ -0,0 +1,460 @@
+//! Resumable uploads, which upload an object in a series of chunks so that a
+//! failure only requires resending the chunk that was in flight rather than the
+//! entire object. See <https://cloud.google.com/storage/docs/performing-resumable-uploads>
+
+use crate::{encryption, hash, util};
+use anyhow::Context as _;
+use std::{io::Read, path::PathBuf, time::Duration};
+use tame_gcs::{
+    self as tgcs, http,
+    objects::{InsertObjectOptional, Metadata, Object, ResumableSession},
+};
+
+/// The size of each chunk sent in a single request, GCS requires that every
+/// chunk apart from the last one is a multiple of 256KiB
+pub const CHUNK_SIZE: usize = 32 * 256 * 1024;
+
+/// The maximum number of times the same chunk is retried before giving up
+const MAX_RETRIES: u32 = 8;
+
+/// The response to a chunk being appended to a resumable upload session, or to
+/// a status query for the session
+enum ChunkResponse {
+    /// The upload is incomplete, and the server has persisted this many bytes
+    Persisted(u64),
+    /// The upload is complete and the object has been created
+    Complete(Box<Metadata>),
+}
+
+impl tgcs::ApiResponse<bytes::Bytes> for ChunkResponse {
+    fn try_from_parts(response: http::Response<bytes::Bytes>) -> Result<Self, tgcs::Error> {
+        if response.status() == http::StatusCode::PERMANENT_REDIRECT {
+            Self::try_from(response)
+        } else {
+            use tgcs::objects::{ResumableInsertResponse, ResumableInsertResponseMetadata as Rirm};
+
+            Ok(
+                match ResumableInsertResponse::try_from_parts(response)?.metadata {
+                    Rirm::PartialSize(size) => Self::Persisted(size),
+                    Rirm::Complete(md) => Self::Complete(md),
+                },
+            )
+        }
+    }
+}
+
+impl TryFrom<http::Response<bytes::Bytes>> for ChunkResponse {
+    type Error = tgcs::Error;
+
+    fn try_from(response: http::Response<bytes::Bytes>) -> Result<Self, Self::Error> {
+        // Unlike tame-gcs, treat a missing Range as the server not having
+        // persisted anything yet, which is what GCS does before the first chunk
+        let Some(range) = response.headers().get(http::header::RANGE) else {
+            return Ok(Self::Persisted(0));
+        };
+
+        let end = range
+            .to_str()
+            .ok()
+            .and_then(|range| range.rsplit('-').next()?.parse::<u64>().ok())
+            .ok_or_else(|| tgcs::Error::OpaqueHeaderValue(range.clone()))?;
+
+        Ok(Self::Persisted(end + 1))
+    }
+}
+
+/// The parameters for a new resumable upload
+pub struct Upload<'a> {
+    /// The destination bucket
+    pub bucket: &'a tgcs::BucketName<'a>,
+    /// The metadata for the object, which must include its name
+    pub metadata: &'a Metadata,
+    /// Metadata that tame-gcs' `Metadata` can't hold
+    pub extra: Option<&'a util::ExtraMetadata>,
+    /// Additional options for the upload
+    pub optional: Option<InsertObjectOptional<'a>>,
+    /// The total length of the object, if known up front
+    pub length: Option<u64>,
+}
+
+/// Initiates a new resumable upload session
+pub async fn start(
+    ctx: &util::RequestContext,
+    upload: &Upload<'_>,
+) -> anyhow::Result<ResumableSession> {
+    let object = tgcs::ObjectName::try_from(
+        upload
+            .metadata
+            .name
+            .as_deref()
+            .context("an object name is required")?,
+    )?;
+
+    let init_req = ctx.obj.resumable_insert_init(
+        &(upload.bucket, &object),
+        upload.metadata.content_type.as_deref(),
+    )?;
+
+    // tame-gcs doesn't let us specify the object metadata or any of the
+    // optional parameters, so add them to the request ourselves
+    let (mut parts, ()) = init_req.into_parts();
+
+    if let Some(optional) = &upload.optional {
+        let query = serde_urlencoded::to_string(optional)?;
+        if !query.is_empty() {
+            parts.uri = format!("{}&{query}", parts.uri).parse()?;
+        }
+    }
+
+    let body = match upload.extra {
+        Some(extra) => serde_json::to_vec(&extra.merge(upload.metadata)?)?,
+        None => serde_json::to_vec(upload.metadata)?,
+    };
+
+    parts.headers.insert(
+        http::header::CONTENT_TYPE,
+        http::HeaderValue::from_static("application/json; charset=UTF-8"),
+    );
+    parts
+        .headers
+        .insert(http::header::CONTENT_LENGTH, body.len().into());
+    encryption::add_headers(&mut parts.headers, ctx.encryption_keys.encryption())?;
+
+    if let Some(length) = upload.length {
+        parts.headers.insert(
+            http::header::HeaderName::from_static("x-upload-content-length"),
+            length.into(),
+        );
+    }
+
+    let init_req = http::Request::from_parts(parts, std::io::Cursor::new(body));
+    let init_res: tgcs::objects::InitResumableInsertResponse = util::execute(ctx, init_req).await?;
+
+    Ok(init_res.resumable_session)
+}
+
+/// Sends the specified bytes, which start at `offset` within the object.
+/// `total` is the length of the object if known, and must always be set when
+/// sending the final chunk, along with the `hashes` of the entire object if
+/// GCS should validate them before finalizing it
+async fn append(
+    ctx: &util::RequestContext,
+    session: &ResumableSession,
+    chunk: bytes::Bytes,
+    offset: u64,
+    total: Option<u64>,
+    hashes: Option<&hash::Hashes>,
+) -> anyhow::Result<ChunkResponse> {
+    let total = total.map_or_else(|| "*".to_owned(), |total| total.to_string());
+    let range = if chunk.is_empty() {
+        format!("bytes */{total}")
+    } else {
+        format!("bytes {offset}-{}/{total}", offset + chunk.len() as u64 - 1)
+    };
+
+    let len = chunk.len() as u64;
+    let mut req = Object::resumable_append(session.clone(), std::io::Cursor::new(chunk), len)?;
+    req.headers_mut().insert(
+        http::header::CONTENT_RANGE,
+        http::HeaderValue::try_from(range)?,
+    );
+
+    if let Some(hashes) = hashes {
+        req.headers_mut().insert(
+            http::header::HeaderName::from_static("x-goog-hash"),
+            http::HeaderValue::try_from(hashes.header_value())?,
+        );
+    }
+
+    // The key has to be sent with every request of an upload that is
+    // encrypted with a customer-supplied key
+    encryption::add_headers(req.headers_mut(), ctx.encryption_keys.encryption())?;
+
+    util::execute(ctx, req).await
+}
+
+/// Queries how many bytes of the upload have been persisted by the server
+async fn status(
+    ctx: &util::RequestContext,
+    session: &ResumableSession,
+    total: Option<u64>,
+) -> anyhow::Result<ChunkResponse> {
+    append(ctx, session, bytes::Bytes::new(), 0, total, None).await
+}
+
+/// Reads up to [`CHUNK_SIZE`] bytes from the source on a blocking thread,
+/// adding them to the hasher
+async fn read_chunk<R>(
+    mut src: R,
+    mut hasher: hash::Hasher,
+) -> anyhow::Result<(bytes::Bytes, R, hash::Hasher)>
+where
+    R: Read + Send + 'static,
+{
+    tokio::task::spawn_blocking(move || {
+        let mut chunk = Vec::with_capacity(CHUNK_SIZE);
+        (&mut src)
+            .take(CHUNK_SIZE as u64)
+            .read_to_end(&mut chunk)
+            .context("failed to read upload source")?;
+        hasher.update(&chunk);
+        Ok((chunk.into(), src, hasher))
+    })
+    .await?
+}
+
+/// Uploads the source to an existing session in chunks, starting at `offset`,
+/// which the source must already be positioned at. Failed chunks are retried
+/// from whatever point the server managed to persist.
+///
+/// The content is hashed as it is read, `hasher` must already contain the
+/// first `offset` bytes of the object. The hashes are sent with the final
+/// chunk so that GCS rejects the object if they don't match what it received,
+/// and are also checked against the metadata of the finished object, which is
+/// returned along with the hasher of the entire content.
+pub async fn upload<R>(
+    ctx: &util::RequestContext,
+    session: &ResumableSession,
+    mut src: R,
+    mut offset: u64,
+    length: Option<u64>,
+    mut hasher: hash::Hasher,
+) -> anyhow::Result<(Metadata, hash::Hasher)>
+where
+    R: Read + Send + 'static,
+{
+    let mut retries = 0;
+
+    loop {
+        let (chunk, source, chunk_hasher) = read_chunk(src, hasher).await?;
+        src = source;
+        hasher = chunk_hasher;
+
+        let chunk_start = offset;
+        let chunk_end = chunk_start + chunk.len() as u64;
+        let is_last = chunk.len() < CHUNK_SIZE || length == Some(chunk_end);
+
+        // The total is always known once we reach the end of the source, even
+        // if we didn't know it up front
+        let total = if is_last { Some(chunk_end) } else { length };
+        let hashes = is_last.then(|| hasher.clone().finish());
+
+        // Keep sending the remainder of the chunk until the server has all of
+        // it, the final chunk is always sent at least once so that the server
+        // knows the upload is complete
+        let mut sent_last = false;
+        while offset < chunk_end || (is_last && !sent_last) {
+            let remaining = chunk.slice((offset - chunk_start) as usize..);
+
+            let res = match append(ctx, session, remaining, offset, total, hashes.as_ref()).await {
+                Ok(res) => {
+                    retries = 0;
+                    sent_last = is_last;
+                    res
+                }
+                Err(err) if retries < MAX_RETRIES && util::is_transient(&err) => {
+                    retries += 1;
+                    tokio::time::sleep(Duration::from_millis(500 * 2u64.pow(retries))).await;
+
+                    match status(ctx, session, total).await {
+                        Ok(res) => res,
+                        // The next attempt at the chunk will also query the
+                        // status if it fails again
+                        Err(err) if util::is_transient(&err) => continue,
+                        Err(err) => return Err(err),
+                    }
+                }
+                Err(err) => return Err(err),
+            };
+
+            match res {
+                ChunkResponse::Complete(md) => {
+                    if let Some(hashes) = &hashes {
+                        hashes
+                            .verify(&hash::ObjectHashes::from_metadata(&md))
+                            .context("uploaded object doesn't match the source")?;
+                    }
+
+                    return Ok((*md, hasher));
+                }
+                ChunkResponse::Persisted(persisted) => {
+                    anyhow::ensure!(
+                        (chunk_start..=chunk_end).contains(&persisted),
+                        "server persisted {persisted} bytes, but only {chunk_start}-{chunk_end} were expected"
+                    );
+
+                    offset = persisted;
+                }
+            }
+        }
+
+        anyhow::ensure!(
+            !is_last,
+            "server did not finalize the object after the last chunk"
+        );
+    }
+}
+
+/// The directory that the sessions of resumable uploads are tracked in, the
+/// same as gsutil's, or `None` if there is no home directory
+fn tracker_dir() -> Option<PathBuf> {
+    let home = std::env::var_os("HOME")
+        .or_else(|| std::env::var_os("USERPROFILE"))
+        .filter(|home| !home.is_empty())?;
+
+    Some(PathBuf::from(home).join(".gsutil").join("tracker-files"))
+}
+
+/// Persists the session URI of a resumable upload of a local file so that if
+/// the upload is interrupted, running the same command again can resume it.
+///
+/// Anyone who has the session URI can upload to it, so sessions are stored in
+/// the user's home directory, and are only readable by the user.
+pub struct Tracker {
+    /// The file the session is stored in, or `None` if there is nowhere to
+    /// store it, in which case uploads just can't be resumed
+    path: Option<PathBuf>,
+    fingerprint: String,
+}
+
+impl Tracker {
+    /// Creates a tracker for an upload of the specified file. The tracker is
+    /// only used if the file hasn't been modified since the tracker was saved,
+    /// and the upload has exactly the same destination and options, as a
+    /// session can't be changed once it has started.
+    pub fn new(
+        ctx: &util::RequestContext,
+        src: &std::path::Path,
+        upload: &Upload<'_>,
+    ) -> anyhow::Result<Self> {
+        use std::hash::{Hash, Hasher};
+
+        let src = src.canonicalize()?;
+        let src_md = src.metadata()?;
+        let modified = src_md
+            .modified()?
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default();
+
+        let metadata = match upload.extra {
+            Some(extra) => extra.merge(upload.metadata)?,
+            None => serde_json::to_value(upload.metadata)?,
+        };
+        let optional = upload
+            .optional
+            .as_ref()
+            .map(serde_urlencoded::to_string)
+            .transpose()?
+            .unwrap_or_default();
+        let key = ctx
+            .encryption_keys
+            .encryption()
+            .map_or("", |key| key.sha256());
+
+        let fingerprint = format!(
+            "{}\n{}\n{}\n{}\n{metadata}\n{optional}\n{key}",
+            src.display(),
+            upload.bucket,
+            src_md.len(),
+            modified.as_nanos()
+        );
+
+        let mut hasher = std::collections::hash_map::DefaultHasher::new();
+        fingerprint.hash(&mut hasher);
+
+        let path = tracker_dir().map(|dir| dir.join(format!("{:016x}", hasher.finish())));
+
+        Ok(Self { path, fingerprint })
+    }
+
+    /// Loads the session for an earlier upload, if there is one
+    pub fn load(&self) -> Option<ResumableSession> {
+        let contents = std::fs::read_to_string(self.path.as_ref()?).ok()?;
+        let (fingerprint, session) = contents.rsplit_once('\n')?;
+
+        if fingerprint != self.fingerprint {
+            return None;
+        }
+
+        session.parse().ok().map(ResumableSession)
+    }
+
+    /// Saves the session so that it can be resumed later. Failing to save the
+    /// session isn't fatal, as it just means the upload can't be resumed.
+    pub fn save(&self, session: &ResumableSession) {
+        let Some(path) = &self.path else {
+            return;
+        };
+
+        let _ = Self::write(path, &format!("{}\n{}", self.fingerprint, session.0));
+    }
+
+    /// Writes the tracker file, creating its directory if needed, so that
+    /// only the user can access either of them
+    fn write(path: &std::path::Path, contents: &str) -> std::io::Result<()> {
+        use std::io::Write as _;
+
+        let mut dir_builder = std::fs::DirBuilder::new();
+        let mut options = std::fs::OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::{DirBuilderExt as _, OpenOptionsExt as _};
+
+            dir_builder.mode(0o700);
+            options.mode(0o600);
+        }
+
+        if let Some(parent) = path.parent() {
+            dir_builder.recursive(true).create(parent)?;
+
+            // The directory may already exist with broader permissions
+            #[cfg(unix)]
+            {
+                use std::os::unix::fs::PermissionsExt as _;
+                std::fs::set_permissions(parent, std::fs::Permissions::from_mode(0o700))?;
+            }
+        }
+
+        options.open(path)?.write_all(contents.as_bytes())
+    }
+
+    /// Removes the saved session once the upload is complete
+    pub fn remove(&self) {
+        if let Some(path) = &self.path {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+pub enum Resume {
+    /// The upload should continue from the specified offset
+    Session(ResumableSession, u64),
+    /// The previous upload actually completed
+    Complete(Box<Metadata>),
+}
+
+/// Either resumes the session saved by the tracker, if the server still
+/// knows about it, or starts a new one
+pub async fn resume_or_start(
+    ctx: &util::RequestContext,
+    upload: &Upload<'_>,
+    tracker: &Tracker,
+) -> anyhow::Result<Resume> {
+    if let Some(session) = tracker.load() {
+        // Sessions expire after a week, or are gone if the upload was
+        // cancelled, in which case we just start over
+        match status(ctx, &session, upload.length).await {
+            Ok(ChunkResponse::Persisted(offset)) => return Ok(Resume::Session(session, offset)),
+            Ok(ChunkResponse::Complete(md)) => return Ok(Resume::Complete(md)),
+            Err(err) if util::error_status(&err).is_some() => {}
+            Err(err) => return Err(err),
+        }
+    }
+
+    let session = start(ctx, upload).await?;
+    tracker.save(&session);
+
+    Ok(Resume::Session(session, 0))
+}
@@ -1,15 +1,25 @@
-use crate::util;
+use crate::{util, wildcard};
 use anyhow::Context as _;
 
 #[derive(clap::Parser, Debug)]
 pub struct Args {
-    /// The gs:// url to the object
-    url: url::Url,
+    /// The gs:// url to the object, which may contain wildcards to remove
+    /// every object that matches
+    url: String,
 }
 
 pub async fn cmd(ctx: &util::RequestContext, args: Args) -> anyhow::Result<()> {
-    let oid = util::gs_url_to_object_id(&args.url)?;
+    let pattern = wildcard::GsPattern::parse(&args.url)?;
 
+    util::for_each_concurrent(ctx, pattern.expand(ctx).await?, |oid| async move {
+        rm(ctx, &oid)
+            .await
+            .with_context(|| format!("failed to remove '{oid}'"))
+    })
+    .await
+}
+
+async fn rm(ctx: &util::RequestContext, oid: &util::GsUrl) -> anyhow::Result<()> {
     let del_req = ctx.obj.delete(
         &(
             oid.bucket(),
@@ -19,6 +29,5 @@ pub async fn cmd(ctx: &util::RequestContext, args: Args) -> anyhow::Result<()> {
     )?;
 
     util::execute::<_, tame_gcs::objects::DeleteObjectResponse>(ctx, del_req).await?;
-
     Ok(())
 }
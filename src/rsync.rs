@@ -0,0 +1,282 @@
+use crate::{
+    cp::{self, DataPath, Location},
+    hash, posix, util, walk,
+};
+use anyhow::Context as _;
+use std::collections::BTreeMap;
+use tame_gcs::objects::Metadata;
+
+#[derive(clap::Parser)]
+#[group(skip)]
+pub struct Args {
+    #[clap(flatten)]
+    opts: cp::Options,
+    #[clap(flatten)]
+    walk: walk::Options,
+    /// Delete files or objects in the destination that don't exist in the
+    /// source
+    #[clap(short = 'd', long)]
+    delete: bool,
+    /// Only print the copies and deletions that would be made, without
+    /// actually making them
+    #[clap(short = 'n', long)]
+    dry_run: bool,
+    /// Always compare checksums of files and objects that are the same size,
+    /// rather than assuming they are the same if their modification times
+    /// match. Files and objects stored with gzip, eg. uploaded with -z or -Z,
+    /// are always compared by modification time, as their checksums are of
+    /// different content.
+    #[clap(short = 'c', long)]
+    checksum: bool,
+    /// A gs: URL or directory path to synchronize from
+    src_url: String,
+    /// A gs: URL or directory path to synchronize to
+    dest_url: String,
+}
+
+/// A file or object found in the source or destination
+enum Entry {
+    Local(walk::LocalFile),
+    Gs(util::GsUrl, Box<Metadata>),
+}
+
+impl Entry {
+    fn size(&self) -> Option<u64> {
+        match self {
+            Self::Local(file) => Some(file.len),
+            Self::Gs(_, md) => md.size,
+        }
+    }
+
+    /// The modification time of the file, or of the file the object was
+    /// uploaded from
+    fn mtime(&self) -> Option<i64> {
+        match self {
+            Self::Local(file) => file.mtime,
+            Self::Gs(_, md) => md.metadata.as_ref()?.get(posix::MTIME_KEY)?.parse().ok(),
+        }
+    }
+
+    /// Whether the entry is an object stored with gzip, whose size and hashes
+    /// are of the compressed content rather than of the file it came from
+    fn is_gzipped(&self) -> bool {
+        match self {
+            Self::Local(_) => false,
+            Self::Gs(_, md) => md.content_encoding.as_deref() == Some("gzip"),
+        }
+    }
+
+    fn into_location(self) -> Location {
+        match self {
+            Self::Local(file) => Location::Local(file.path),
+            Self::Gs(url, _) => Location::Gs(url),
+        }
+    }
+}
+
+/// Makes the contents of the destination match the source by copying every
+/// file or object that is missing or differs in the destination, and
+/// optionally deleting any that don't exist in the source. Both the source and
+/// destination are always treated as directories.
+pub async fn cmd(ctx: &util::RequestContext, args: Args) -> anyhow::Result<()> {
+    let src = DataPath::try_from(args.src_url.clone())?;
+    let dst = DataPath::try_from(args.dest_url.clone())?;
+
+    anyhow::ensure!(
+        !src.is_local() || !dst.is_local(),
+        "source and destination are both located on local disk"
+    );
+
+    let src_entries = list(ctx, &src, &args.walk, true)
+        .await
+        .with_context(|| format!("failed to list source '{}'", args.src_url))?;
+    let mut dst_entries = list(ctx, &dst, &args.walk, false)
+        .await
+        .with_context(|| format!("failed to list destination '{}'", args.dest_url))?;
+
+    let mut copies = Vec::new();
+    for (relative, src_entry) in src_entries {
+        let needs_copy = match dst_entries.remove(&relative) {
+            Some(dst_entry) => differs(&src_entry, &dst_entry, args.checksum).await?,
+            None => true,
+        };
+
+        if needs_copy {
+            copies.push((
+                src_entry.into_location(),
+                cp::destination(&dst, Some(&relative))?,
+            ));
+        }
+    }
+
+    // Everything left in the destination doesn't exist in the source
+    let deletions = if args.delete {
+        dst_entries
+            .into_values()
+            .map(Entry::into_location)
+            .collect()
+    } else {
+        Vec::new()
+    };
+
+    if args.dry_run {
+        for (src, dst) in &copies {
+            println!("Would copy {src} to {dst}");
+        }
+        for dst in &deletions {
+            println!("Would remove {dst}");
+        }
+
+        return Ok(());
+    }
+
+    let opts = &args.opts;
+
+    util::for_each_concurrent(ctx, copies, |(src, dst)| async move {
+        println!("Copying {src} to {dst}");
+        cp::copy(ctx, opts, &cp::Preconditions::default(), &src, &dst)
+            .await
+            .with_context(|| format!("failed to copy '{src}' to '{dst}'"))
+    })
+    .await?;
+
+    // Deletions are only made once every copy has succeeded
+    util::for_each_concurrent(ctx, deletions, |dst| async move {
+        println!("Removing {dst}");
+        remove(ctx, &dst)
+            .await
+            .with_context(|| format!("failed to remove '{dst}'"))
+    })
+    .await
+}
+
+/// Lists every file or object beneath the path, keyed by their name relative
+/// to it. A source must exist, but a destination that doesn't exist yet is
+/// just empty.
+async fn list(
+    ctx: &util::RequestContext,
+    path: &DataPath,
+    walk_opts: &walk::Options,
+    is_src: bool,
+) -> anyhow::Result<BTreeMap<String, Entry>> {
+    match path {
+        DataPath::Stdio => anyhow::bail!("stdin and stdout can't be synchronized"),
+        DataPath::Local(dir) => {
+            if !is_src && !dir.exists() {
+                return Ok(BTreeMap::new());
+            }
+
+            anyhow::ensure!(dir.is_dir(), "'{}' is not a directory", dir.display());
+
+            Ok(walk::walk(dir, walk_opts)?
+                .into_iter()
+                .map(|file| (file.relative.clone(), Entry::Local(file)))
+                .collect())
+        }
+        DataPath::Gs(pattern) => {
+            anyhow::ensure!(
+                !pattern.has_wildcard(),
+                "'{pattern}' can't contain wildcards"
+            );
+
+            let url = pattern.to_url()?;
+            let prefix = cp::dir_prefix(url.object());
+
+            let objects = util::list_objects(
+                ctx,
+                url.bucket(),
+                &prefix,
+                None,
+                Some("name, size, crc32c, md5Hash, contentEncoding, metadata"),
+            )
+            .await?;
+
+            let mut entries = BTreeMap::new();
+            for md in objects {
+                let name = md.name.clone().context("object is missing a name")?;
+                let relative = name[prefix.len()..].to_owned();
+
+                // Skip placeholder objects for "directories"
+                if relative.is_empty()
+                    || relative.ends_with('/')
+                    || walk_opts.is_excluded(&relative)
+                {
+                    continue;
+                }
+
+                entries.insert(relative, Entry::Gs(url.with_object(name)?, Box::new(md)));
+            }
+
+            Ok(entries)
+        }
+    }
+}
+
+/// Determines if the destination differs from the source. Entries of
+/// different sizes always differ, and unless `checksum` is set, entries with
+/// the same modification time are assumed to be the same. Otherwise their
+/// crc32c, or md5 if an object doesn't have a crc32c, are compared. Files
+/// and objects stored with gzip are only compared by modification time.
+async fn differs(src: &Entry, dst: &Entry, checksum: bool) -> anyhow::Result<bool> {
+    // Files uploaded with -z or -Z can't be compared with their objects by
+    // size or hash, as the object has the compressed content, so only their
+    // modification times can be compared
+    if src.is_gzipped() != dst.is_gzipped() {
+        return Ok(!matches!((src.mtime(), dst.mtime()), (Some(src), Some(dst)) if src == dst));
+    }
+
+    if src.size() != dst.size() {
+        return Ok(true);
+    }
+
+    if !checksum {
+        if let (Some(src_mtime), Some(dst_mtime)) = (src.mtime(), dst.mtime()) {
+            if src_mtime == dst_mtime {
+                return Ok(false);
+            }
+        }
+    }
+
+    match (src, dst) {
+        (Entry::Gs(_, src), Entry::Gs(_, dst)) => {
+            if let (Some(src), Some(dst)) = (&src.crc32c, &dst.crc32c) {
+                return Ok(src != dst);
+            }
+
+            match (&src.md5_hash, &dst.md5_hash) {
+                (Some(src), Some(dst)) => Ok(src != dst),
+                _ => Ok(true),
+            }
+        }
+        (Entry::Local(file), Entry::Gs(_, md)) | (Entry::Gs(_, md), Entry::Local(file)) => {
+            let path = file.path.clone();
+            let md5 = md.crc32c.is_none();
+            let hashes = tokio::task::spawn_blocking(move || hash::hash_file(&path, md5)).await??;
+
+            Ok(hashes.matches(&hash::ObjectHashes::from_metadata(md)) != Some(true))
+        }
+        (Entry::Local(_), Entry::Local(_)) => {
+            anyhow::bail!("source and destination are both located on local disk")
+        }
+    }
+}
+
+/// Removes a single file or object from the destination
+async fn remove(ctx: &util::RequestContext, dst: &Location) -> anyhow::Result<()> {
+    match dst {
+        Location::Local(path) => Ok(std::fs::remove_file(path)?),
+        Location::Stdio => anyhow::bail!("stdout can't be removed"),
+        Location::Gs(url) => {
+            let del_req = ctx.obj.delete(
+                &(
+                    url.bucket(),
+                    url.object().context("invalid object name specified")?,
+                ),
+                None,
+            )?;
+
+            util::execute::<_, tame_gcs::objects::DeleteObjectResponse>(ctx, del_req).await?;
+            Ok(())
+        }
+    }
+}
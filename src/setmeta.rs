@@ -1,74 +1,118 @@
-use crate::{color::ColorCtx, util};
+use crate::{color::ColorCtx, encryption, util, wildcard};
 use anyhow::Context as _;
+use std::io::Write as _;
 
 #[derive(clap::Parser, Debug)]
 pub struct Args {
     /// A valid JSON payload for the metadata to set
     json: String,
-    /// The gs:// url to the object to set metadata for
-    url: url::Url,
+    /// The gs:// url to the object to set metadata for, which may contain
+    /// wildcards to set the metadata for every object that matches
+    url: String,
 }
 
 pub async fn cmd(ctx: &util::RequestContext, args: Args) -> anyhow::Result<()> {
     let cc = ColorCtx::from_env();
 
-    let oid = util::gs_url_to_object_id(&args.url)?;
+    let pattern = wildcard::GsPattern::parse(&args.url)?;
 
     let md: tame_gcs::objects::Metadata = serde_json::from_str(&args.json)?;
 
-    let set_req = ctx.obj.patch(
+    let md = &md;
+
+    util::for_each_concurrent(ctx, pattern.expand(ctx).await?, |oid| async move {
+        setmeta(ctx, &cc, &oid, md)
+            .await
+            .with_context(|| format!("failed to set metadata on '{oid}'"))
+    })
+    .await
+}
+
+async fn setmeta(
+    ctx: &util::RequestContext,
+    cc: &ColorCtx,
+    oid: &util::GsUrl,
+    md: &tame_gcs::objects::Metadata,
+) -> anyhow::Result<()> {
+    let mut set_req = ctx.obj.patch(
         &(
             oid.bucket(),
             oid.object().context("invalid object name specified")?,
         ),
-        &md,
+        md,
         None,
     )?;
 
-    let get_res: tame_gcs::objects::PatchObjectResponse = util::execute(ctx, set_req).await?;
+    // The key is only needed for the hashes in the response
+    let key = encryption::supplied_key_for(ctx, oid, None).await?;
+    encryption::add_headers(set_req.headers_mut(), key.as_ref())?;
 
-    let md = get_res.metadata;
+    let util::JsonResponse(json) = util::execute(ctx, set_req).await?;
+    let (md, storage_class) = util::object_metadata(json)?;
 
-    // Print out the information the same way gsutil does, except with RFC-2822 date formatting
-    println!("{}", cc.paint(nu_ansi_term::Color::Cyan, args.url.as_str()));
-    println!(
+    // Print out the information the same way gsutil does, except with RFC-2822 date formatting.
+    // stdout is locked for the whole object so that concurrent operations don't interleave
+    let mut out = std::io::stdout().lock();
+    writeln!(
+        out,
+        "{}",
+        cc.paint(nu_ansi_term::Color::Cyan, oid.to_string())
+    )?;
+    writeln!(
+        out,
         "    Creation time:\t{}",
         md.time_created
             .expect("time_created")
             .format(&time::format_description::well_known::Rfc2822)
             .unwrap()
-    );
-    println!(
+    )?;
+    writeln!(
+        out,
         "    Update time:\t{}",
         md.updated
             .expect("updated")
             .format(&time::format_description::well_known::Rfc2822)
             .unwrap()
-    );
-    println!(
+    )?;
+    writeln!(
+        out,
         "    Storage class:\t{}",
-        md.storage_class.expect("storage_class")
-    );
-    println!("    Content-Length:\t{}", md.size.expect("size"));
-    println!(
+        storage_class.as_deref().unwrap_or("None")
+    )?;
+    writeln!(out, "    Content-Length:\t{}", md.size.expect("size"))?;
+    writeln!(
+        out,
         "    Content-Type:\t{}",
         md.content_type.as_deref().unwrap_or("None")
-    );
+    )?;
 
     if let Some(md) = &md.metadata {
         for (k, v) in md {
-            println!("        {k}:\t\t{v}");
+            writeln!(out, "        {k}:\t\t{v}")?;
         }
     }
 
-    println!("    Hash (crc32c):\t{}", md.crc32c.expect("crc32c"));
-    println!("    Hash (md5):\t\t{}", md.md5_hash.expect("md5_hash"));
-    println!("    ETag:\t\t{}", md.etag.expect("etag"));
-    println!("    Generation:\t\t{}", md.generation.expect("generation"));
-    println!(
+    writeln!(
+        out,
+        "    Hash (crc32c):\t{}",
+        md.crc32c.as_deref().unwrap_or("None")
+    )?;
+    writeln!(
+        out,
+        "    Hash (md5):\t\t{}",
+        md.md5_hash.as_deref().unwrap_or("None")
+    )?;
+    writeln!(out, "    ETag:\t\t{}", md.etag.expect("etag"))?;
+    writeln!(
+        out,
+        "    Generation:\t\t{}",
+        md.generation.expect("generation")
+    )?;
+    writeln!(
+        out,
         "    Metageneration:\t{}",
         md.metageneration.expect("metageneration")
-    );
+    )?;
 
     Ok(())
 }
@@ -1,67 +1,136 @@
-use crate::{color::ColorCtx, util};
+use crate::{color::ColorCtx, encryption, util, wildcard};
 use anyhow::Context as _;
+use std::io::Write as _;
 
 #[derive(clap::Parser, Debug)]
 pub struct Args {
-    /// The gs:// url to the object to stat
-    url: url::Url,
+    /// The gs:// url to the object to stat, which may contain wildcards to
+    /// stat every object that matches
+    url: String,
 }
 
 pub async fn cmd(ctx: &util::RequestContext, args: Args) -> anyhow::Result<()> {
-    let oid = util::gs_url_to_object_id(&args.url)?;
+    let pattern = wildcard::GsPattern::parse(&args.url)?;
     let cc = ColorCtx::from_env();
 
-    let get_req = ctx.obj.get(
+    util::for_each_concurrent(ctx, pattern.expand(ctx).await?, |oid| async move {
+        stat(ctx, &cc, &oid)
+            .await
+            .with_context(|| format!("failed to stat '{oid}'"))
+    })
+    .await
+}
+
+fn get_request(
+    ctx: &util::RequestContext,
+    oid: &util::GsUrl,
+    key: Option<&encryption::Key>,
+) -> anyhow::Result<tame_gcs::http::Request<std::io::Empty>> {
+    let mut get_req = ctx.obj.get(
         &(
             oid.bucket(),
             oid.object().context("invalid object name specified")?,
         ),
         None,
     )?;
-    let get_res: tame_gcs::objects::GetObjectResponse = util::execute(ctx, get_req).await?;
+    encryption::add_headers(get_req.headers_mut(), key)?;
+
+    Ok(get_req)
+}
 
-    let md = get_res.metadata;
+async fn stat(ctx: &util::RequestContext, cc: &ColorCtx, oid: &util::GsUrl) -> anyhow::Result<()> {
+    // tame-gcs' Metadata doesn't have the customerEncryption field
+    let util::JsonResponse(json) = util::execute(ctx, get_request(ctx, oid, None)?).await?;
+    let key_sha256 = json["customerEncryption"]["keySha256"]
+        .as_str()
+        .map(str::to_owned);
 
-    // Print out the information the same way gsutil does, except with RFC-2822 date formatting
-    println!("{}", cc.paint(nu_ansi_term::Color::Cyan, args.url.as_str()));
-    println!(
+    // The key is needed for the hashes
+    let json = match key_sha256
+        .as_deref()
+        .and_then(|sha256| ctx.encryption_keys.find(sha256))
+    {
+        Some(key) => {
+            let util::JsonResponse(json) =
+                util::execute(ctx, get_request(ctx, oid, Some(key))?).await?;
+            json
+        }
+        None => json,
+    };
+    let (md, storage_class) = util::object_metadata(json)?;
+    let missing_hash = if key_sha256.is_some() {
+        "encrypted"
+    } else {
+        "None"
+    };
+
+    // Print out the information the same way gsutil does, except with RFC-2822 date formatting.
+    // stdout is locked for the whole object so that concurrent operations don't interleave
+    let mut out = std::io::stdout().lock();
+    writeln!(
+        out,
+        "{}",
+        cc.paint(nu_ansi_term::Color::Cyan, oid.to_string())
+    )?;
+    writeln!(
+        out,
         "    Creation time:\t{}",
         md.time_created
             .expect("time_created")
             .format(&time::format_description::well_known::Rfc2822)
             .unwrap()
-    );
-    println!(
+    )?;
+    writeln!(
+        out,
         "    Update time:\t{}",
         md.updated
             .expect("updated")
             .format(&time::format_description::well_known::Rfc2822)
             .unwrap()
-    );
-    println!(
+    )?;
+    writeln!(
+        out,
         "    Storage class:\t{}",
-        md.storage_class.expect("storage_class")
-    );
-    println!("    Content-Length:\t{}", md.size.expect("size"));
-    println!(
+        storage_class.as_deref().unwrap_or("None")
+    )?;
+    writeln!(out, "    Content-Length:\t{}", md.size.expect("size"))?;
+    writeln!(
+        out,
         "    Content-Type:\t{}",
         md.content_type.as_deref().unwrap_or("None")
-    );
+    )?;
 
     if let Some(md) = &md.metadata {
         for (k, v) in md {
-            println!("        {k}:\t\t{v}");
+            writeln!(out, "        {k}:\t\t{v}")?;
         }
     }
 
-    println!("    Hash (crc32c):\t{}", md.crc32c.expect("crc32c"));
-    println!("    Hash (md5):\t\t{}", md.md5_hash.expect("md5_hash"));
-    println!("    ETag:\t\t{}", md.etag.expect("etag"));
-    println!("    Generation:\t\t{}", md.generation.expect("generation"));
-    println!(
+    writeln!(
+        out,
+        "    Hash (crc32c):\t{}",
+        md.crc32c.as_deref().unwrap_or(missing_hash)
+    )?;
+    writeln!(
+        out,
+        "    Hash (md5):\t\t{}",
+        md.md5_hash.as_deref().unwrap_or(missing_hash)
+    )?;
+    if let Some(sha256) = &key_sha256 {
+        writeln!(out, "    Encryption algorithm:\tAES256")?;
+        writeln!(out, "    Encryption key SHA256:\t{sha256}")?;
+    }
+    writeln!(out, "    ETag:\t\t{}", md.etag.expect("etag"))?;
+    writeln!(
+        out,
+        "    Generation:\t\t{}",
+        md.generation.expect("generation")
+    )?;
+    writeln!(
+        out,
         "    Metageneration:\t{}",
         md.metageneration.expect("metageneration")
-    );
+    )?;
 
     Ok(())
 }
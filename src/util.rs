@@ -3,14 +3,48 @@ use std::sync::Arc;
 use tame_gcs::{self as tgcs, http};
 use tame_oauth::gcp as oauth;
 
-/// Converts a vanilla `http::Request` into a `reqwest::Request`
+/// The size of the blocks read from a request body and sent as a single chunk
+const BODY_BLOCK_SIZE: usize = 64 * 1024;
+
+/// The maximum number of blocks that can be read ahead of the blocks that have
+/// actually been sent, which bounds the memory used by a request body
+const BODY_BLOCKS_IN_FLIGHT: usize = 4;
+
+/// Reads the next block from a (blocking) request body, returning `None` once
+/// the body has been exhausted
+fn read_block<B: std::io::Read>(body: &mut B) -> std::io::Result<Option<bytes::Bytes>> {
+    let mut block = vec![0u8; BODY_BLOCK_SIZE];
+
+    let read = loop {
+        match body.read(&mut block) {
+            Ok(read) => break read,
+            Err(err) if err.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(err) => return Err(err),
+        }
+    };
+
+    if read == 0 {
+        return Ok(None);
+    }
+
+    block.truncate(read);
+    Ok(Some(block.into()))
+}
+
+/// Converts a vanilla `http::Request` into a `reqwest::Request`. The body is
+/// read on a blocking thread and streamed to reqwest, so only a few blocks are
+/// ever held in memory regardless of the size of the body. Each block is only
+/// sent once the rate limit allows it.
 async fn convert_request<B>(
     req: http::Request<B>,
     client: &reqwest::Client,
+    rate_limit: &crate::ratelimit::RateLimit,
 ) -> anyhow::Result<reqwest::Request>
 where
     B: std::io::Read + Send + 'static,
 {
+    use futures_util::StreamExt;
+
     let (parts, mut body) = req.into_parts();
 
     let uri = parts.uri.to_string();
@@ -24,25 +58,60 @@ where
         method => anyhow::bail!("'{method}' not implemented"),
     };
 
-    let content_len = tame_gcs::util::get_content_length(&parts.headers).unwrap_or(0);
-    let mut buffer = bytes::BytesMut::with_capacity(content_len);
+    let builder = builder.headers(parts.headers);
 
-    let mut block = [0u8; 8 * 1024];
+    // Read the first block up front so that requests without a body, eg. GET,
+    // are sent without one rather than with an empty chunked body
+    let (first, body) = tokio::task::spawn_blocking(move || {
+        let first = read_block(&mut body);
+        (first, body)
+    })
+    .await?;
 
-    loop {
-        let read = body.read(&mut block)?;
+    let Some(first) = first.context("failed to read request body")? else {
+        return Ok(builder.build()?);
+    };
 
-        if read > 0 {
-            buffer.extend_from_slice(&block[..read]);
-        } else {
-            break;
+    let (tx, rx) = tokio::sync::mpsc::channel(BODY_BLOCKS_IN_FLIGHT);
+
+    tokio::task::spawn_blocking(move || {
+        let mut body = body;
+
+        loop {
+            let block = match read_block(&mut body) {
+                Ok(Some(block)) => Ok(block),
+                Ok(None) => break,
+                Err(err) => Err(err),
+            };
+
+            let is_err = block.is_err();
+
+            // If the receiver is gone the request has been aborted, so there
+            // is no point in reading the rest of the body
+            if tx.blocking_send(block).is_err() || is_err {
+                break;
+            }
         }
-    }
+    });
+
+    let rest = futures_util::stream::unfold(rx, |mut rx| async move {
+        rx.recv().await.map(|block| (block, rx))
+    });
 
-    Ok(builder
-        .headers(parts.headers)
-        .body(buffer.freeze())
-        .build()?)
+    let rate_limit = rate_limit.clone();
+    let stream = futures_util::stream::once(async move { Ok(first) })
+        .chain(rest)
+        .then(move |block| {
+            let rate_limit = rate_limit.clone();
+            async move {
+                if let Ok(block) = &block {
+                    rate_limit.acquire(block.len() as u64).await;
+                }
+                block
+            }
+        });
+
+    Ok(builder.body(reqwest::Body::wrap_stream(stream)).build()?)
 }
 
 /// Converts a `reqwest::Response` into a vanilla `http::Response`. This currently copies
@@ -82,12 +151,23 @@ pub struct RequestContext {
     pub client: reqwest::Client,
     pub auth: Arc<oauth::TokenProviderWrapper>,
     pub obj: tgcs::objects::Object,
+    /// The maximum number of operations that commands run concurrently
+    pub jobs: usize,
+    /// The customer-supplied keys used to encrypt and decrypt objects
+    pub encryption_keys: crate::encryption::Keys,
+    /// Records the bytes transferred by commands that report their progress
+    pub progress: crate::progress::Progress,
+    /// Limits the rate that request and response bodies are transferred at
+    pub rate_limit: crate::ratelimit::RateLimit,
 }
 
-/// Executes a GCS request via a reqwest client and returns the parsed response/API error
-pub async fn execute<B, R>(ctx: &RequestContext, mut req: http::Request<B>) -> anyhow::Result<R>
+/// Sends a GCS request via a reqwest client, after authorizing it, and returns
+/// the response with its body not yet read
+async fn send<B>(
+    ctx: &RequestContext,
+    mut req: http::Request<B>,
+) -> anyhow::Result<reqwest::Response>
 where
-    R: tgcs::ApiResponse<bytes::Bytes>,
     B: std::io::Read + Send + 'static,
 {
     use oauth::TokenProvider;
@@ -105,7 +185,7 @@ where
             let read_body = std::io::Cursor::new(body);
             let new_request = http::Request::from_parts(parts, read_body);
 
-            let req = convert_request(new_request, &ctx.client)
+            let req = convert_request(new_request, &ctx.client, &ctx.rate_limit)
                 .await
                 .context("failed to create token request")?;
             let res = ctx
@@ -129,8 +209,17 @@ where
     req.headers_mut()
         .insert(http::header::AUTHORIZATION, token.try_into()?);
 
-    let request = convert_request(req, &ctx.client).await?;
-    let response = ctx.client.execute(request).await?;
+    let request = convert_request(req, &ctx.client, &ctx.rate_limit).await?;
+    Ok(ctx.client.execute(request).await?)
+}
+
+/// Executes a GCS request via a reqwest client and returns the parsed response/API error
+pub async fn execute<B, R>(ctx: &RequestContext, req: http::Request<B>) -> anyhow::Result<R>
+where
+    R: tgcs::ApiResponse<bytes::Bytes>,
+    B: std::io::Read + Send + 'static,
+{
+    let response = send(ctx, req).await?;
     let response = convert_response(response)
         .await
         .context("failed to convert response")?;
@@ -138,12 +227,217 @@ where
     Ok(R::try_from_parts(response)?)
 }
 
+/// A successful response whose body is read in chunks as they arrive rather
+/// than being buffered in its entirety
+pub struct StreamingResponse {
+    response: reqwest::Response,
+    progress: crate::progress::Progress,
+    rate_limit: crate::ratelimit::RateLimit,
+}
+
+impl StreamingResponse {
+    #[inline]
+    pub fn headers(&self) -> &http::HeaderMap {
+        self.response.headers()
+    }
+
+    /// Retrieves the next chunk of the body, or `None` once it has been
+    /// completely read
+    pub async fn chunk(&mut self) -> anyhow::Result<Option<bytes::Bytes>> {
+        let chunk = self
+            .response
+            .chunk()
+            .await
+            .context("failed to read response body")?;
+
+        if let Some(chunk) = &chunk {
+            self.rate_limit.acquire(chunk.len() as u64).await;
+            self.progress.add(chunk.len() as u64);
+        }
+
+        Ok(chunk)
+    }
+
+    /// Writes the entire body to the specified writer as it arrives, returning
+    /// the number of bytes that were written
+    pub async fn copy_to<W: std::io::Write>(mut self, w: &mut W) -> anyhow::Result<u64> {
+        let mut written = 0;
+
+        while let Some(chunk) = self.chunk().await? {
+            w.write_all(&chunk)?;
+            written += chunk.len() as u64;
+        }
+
+        w.flush()?;
+        Ok(written)
+    }
+}
+
+/// Executes a GCS request via a reqwest client, but unlike [`execute`] the body
+/// of a successful response is streamed rather than read into memory. This is
+/// intended for downloads, where the body can be arbitrarily large.
+pub async fn execute_streaming<B>(
+    ctx: &RequestContext,
+    req: http::Request<B>,
+) -> anyhow::Result<StreamingResponse>
+where
+    B: std::io::Read + Send + 'static,
+{
+    use tgcs::ApiResponse;
+
+    let response = send(ctx, req).await?;
+
+    if !response.status().is_success() {
+        let response = convert_response(response)
+            .await
+            .context("failed to convert response")?;
+
+        // Error responses are small, so let tame-gcs parse the API error
+        // exactly like a regular response would be
+        tgcs::objects::DownloadObjectResponse::try_from_parts(response)?;
+        anyhow::bail!("unexpected response status");
+    }
+
+    Ok(StreamingResponse {
+        response,
+        progress: ctx.progress.clone(),
+        rate_limit: ctx.rate_limit.clone(),
+    })
+}
+
+/// Runs an operation for each of the items, with up to [`RequestContext::jobs`]
+/// of them in flight at once. A failed operation doesn't stop the others, its
+/// error is printed as soon as it happens, and an error is returned once every
+/// operation has finished if any of them failed. If there is only a single
+/// item its error is returned as is.
+pub async fn for_each_concurrent<I, F, Fut>(
+    ctx: &RequestContext,
+    items: I,
+    mut op: F,
+) -> anyhow::Result<()>
+where
+    I: IntoIterator,
+    F: FnMut(I::Item) -> Fut,
+    Fut: std::future::Future<Output = anyhow::Result<()>>,
+{
+    use futures_util::StreamExt;
+
+    let mut items: Vec<_> = items.into_iter().collect();
+    if items.len() == 1 {
+        return op(items.remove(0)).await;
+    }
+
+    let cc = crate::color::ColorCtx::from_env();
+    let total = items.len();
+    let mut failed = 0;
+
+    let mut results = futures_util::stream::iter(items)
+        .map(op)
+        .buffer_unordered(ctx.jobs.max(1));
+
+    while let Some(res) = results.next().await {
+        if let Err(err) = res {
+            failed += 1;
+            eprintln!(
+                "{}",
+                cc.paint_err(nu_ansi_term::Color::Red, format!("{err:?}"))
+            );
+        }
+    }
+
+    anyhow::ensure!(failed == 0, "{failed} of {total} operations failed");
+    Ok(())
+}
+
+/// A response whose JSON body is kept as is, for when we need fields that
+/// tame-gcs doesn't know about, or need to pass the body back to GCS without
+/// losing any of them
+pub struct JsonResponse(pub serde_json::Value);
+
+impl tgcs::ApiResponse<bytes::Bytes> for JsonResponse {}
+
+impl TryFrom<http::Response<bytes::Bytes>> for JsonResponse {
+    type Error = tgcs::Error;
+
+    fn try_from(response: http::Response<bytes::Bytes>) -> Result<Self, Self::Error> {
+        Ok(Self(serde_json::from_slice(response.body())?))
+    }
+}
+
+/// The fields of an object's metadata that are requested in responses that are
+/// deserialized into tame-gcs' `Metadata`. Its `StorageClass` can't deserialize
+/// `ARCHIVE`, so the storage class is excluded, and is instead read from raw
+/// JSON responses with [`object_metadata`] and sent to the API as a plain
+/// string.
+pub const OBJECT_FIELDS: &str =
+    "name, generation, metageneration, size, crc32c, md5Hash, componentCount";
+
+/// Deserializes the object metadata in a raw JSON response, taking the
+/// storage class out as a plain string first, see [`OBJECT_FIELDS`]
+pub fn object_metadata(
+    mut json: serde_json::Value,
+) -> anyhow::Result<(tgcs::objects::Metadata, Option<String>)> {
+    let storage_class = json
+        .as_object_mut()
+        .and_then(|obj| obj.remove("storageClass"))
+        .and_then(|storage_class| storage_class.as_str().map(str::to_owned));
+
+    Ok((serde_json::from_value(json)?, storage_class))
+}
+
+/// Writable object metadata that tame-gcs' `Metadata` doesn't have fields
+/// for, which can only be set by requests whose JSON body we build ourselves
+#[derive(Default, Clone)]
+pub struct ExtraMetadata {
+    pub cache_control: Option<String>,
+    /// An RFC 3339 timestamp
+    pub custom_time: Option<String>,
+    /// See [`OBJECT_FIELDS`] for why this isn't part of `Metadata`
+    pub storage_class: Option<String>,
+}
+
+impl ExtraMetadata {
+    pub fn is_empty(&self) -> bool {
+        self.cache_control.is_none() && self.custom_time.is_none() && self.storage_class.is_none()
+    }
+
+    /// Serializes the metadata along with the extra fields
+    pub fn merge(&self, md: &tgcs::objects::Metadata) -> anyhow::Result<serde_json::Value> {
+        let mut json = serde_json::to_value(md)?;
+        let obj = json
+            .as_object_mut()
+            .context("metadata didn't serialize to an object")?;
+
+        if let Some(cache_control) = &self.cache_control {
+            obj.insert("cacheControl".to_owned(), cache_control.clone().into());
+        }
+        if let Some(custom_time) = &self.custom_time {
+            obj.insert("customTime".to_owned(), custom_time.clone().into());
+        }
+        if let Some(storage_class) = &self.storage_class {
+            obj.insert("storageClass".to_owned(), storage_class.clone().into());
+        }
+
+        Ok(json)
+    }
+}
+
 pub struct GsUrl {
     bucket_name: tgcs::BucketName<'static>,
     obj_name: Option<tgcs::ObjectName<'static>>,
 }
 
 impl GsUrl {
+    pub fn new(
+        bucket_name: tgcs::BucketName<'static>,
+        obj_name: Option<tgcs::ObjectName<'static>>,
+    ) -> Self {
+        Self {
+            bucket_name,
+            obj_name,
+        }
+    }
+
     pub fn bucket(&self) -> &tame_gcs::BucketName<'_> {
         &self.bucket_name
     }
@@ -151,6 +445,114 @@ impl GsUrl {
     pub fn object(&self) -> Option<&tame_gcs::ObjectName<'_>> {
         self.obj_name.as_ref()
     }
+
+    /// Creates a url for the specified object in the same bucket
+    pub fn with_object(&self, name: String) -> anyhow::Result<Self> {
+        Ok(Self {
+            bucket_name: tgcs::BucketName::try_from(self.bucket_name.to_string())?,
+            obj_name: Some(tgcs::ObjectName::try_from(name)?),
+        })
+    }
+}
+
+impl std::fmt::Display for GsUrl {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "gs://{}/", self.bucket_name)?;
+
+        if let Some(obj_name) = &self.obj_name {
+            f.write_str(obj_name.as_ref())?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Lists every object in the bucket whose name begins with the specified
+/// prefix, and matches the glob if one is specified, paging through the
+/// results until there are none left. If `item_fields` is specified, only those
+/// metadata fields are retrieved for each object, eg. `"name, size"`
+pub async fn list_objects(
+    ctx: &RequestContext,
+    bucket: &tgcs::BucketName<'_>,
+    prefix: &str,
+    match_glob: Option<&str>,
+    item_fields: Option<&str>,
+) -> anyhow::Result<Vec<tgcs::objects::Metadata>> {
+    let (objects, _) = list(ctx, bucket, prefix, None, match_glob, item_fields).await?;
+    Ok(objects)
+}
+
+/// Lists the objects directly beneath the prefix, ie. whose names don't
+/// contain the delimiter after the prefix, along with the prefixes of the
+/// "directories" beneath it, see [`list_objects`]
+pub async fn list_objects_delimited(
+    ctx: &RequestContext,
+    bucket: &tgcs::BucketName<'_>,
+    prefix: &str,
+    delimiter: &str,
+    item_fields: Option<&str>,
+) -> anyhow::Result<(Vec<tgcs::objects::Metadata>, Vec<String>)> {
+    list(ctx, bucket, prefix, Some(delimiter), None, item_fields).await
+}
+
+/// Pages through a listing, which only includes prefixes if there is a
+/// delimiter
+async fn list(
+    ctx: &RequestContext,
+    bucket: &tgcs::BucketName<'_>,
+    prefix: &str,
+    delimiter: Option<&str>,
+    match_glob: Option<&str>,
+    item_fields: Option<&str>,
+) -> anyhow::Result<(Vec<tgcs::objects::Metadata>, Vec<String>)> {
+    let fields = item_fields.map(|item_fields| {
+        if delimiter.is_some() {
+            format!("items({item_fields}), prefixes, nextPageToken")
+        } else {
+            format!("items({item_fields}), nextPageToken")
+        }
+    });
+
+    let mut objects = Vec::new();
+    let mut prefixes = Vec::new();
+    let mut page_token: Option<String> = None;
+
+    loop {
+        let mut ls_req = ctx.obj.list(
+            bucket,
+            Some(tgcs::objects::ListOptional {
+                delimiter,
+                page_token: page_token.as_deref(),
+                prefix: Some(prefix),
+                standard_params: tgcs::common::StandardQueryParameters {
+                    fields: fields.as_deref(),
+                    ..Default::default()
+                },
+                ..Default::default()
+            }),
+        )?;
+
+        // tame-gcs doesn't support matchGlob, so add it to the query ourselves
+        if let Some(glob) = match_glob {
+            let glob: String = url::form_urlencoded::byte_serialize(glob.as_bytes()).collect();
+            let uri = ls_req.uri();
+            let separator = if uri.query().is_some() { '&' } else { '?' };
+            *ls_req.uri_mut() = format!("{uri}{separator}matchGlob={glob}").parse()?;
+        }
+
+        let ls_res: tgcs::objects::ListResponse = execute(ctx, ls_req).await?;
+        objects.extend(ls_res.objects);
+        prefixes.extend(ls_res.prefixes);
+
+        // If we have a page token it means there may be more items
+        // that fulfill the parameters
+        page_token = ls_res.page_token;
+        if page_token.is_none() {
+            break;
+        }
+    }
+
+    Ok((objects, prefixes))
 }
 
 /// Converts a `gs://<bucket_name>/<object_name>` url into a regular object identifier
@@ -173,3 +575,116 @@ pub fn gs_url_to_object_id(url: &url::Url) -> anyhow::Result<GsUrl> {
         scheme => anyhow::bail!("invalid url scheme: {scheme}"),
     }
 }
+
+/// The modification time of a file in unix seconds, if the platform supports it
+pub fn unix_mtime(md: &std::fs::Metadata) -> Option<i64> {
+    let modified = md.modified().ok()?;
+
+    Some(match modified.duration_since(std::time::UNIX_EPOCH) {
+        Ok(after) => after.as_secs() as i64,
+        Err(before) => -(before.duration().as_secs() as i64),
+    })
+}
+
+/// Gets the HTTP status code the server responded with, if that is the reason
+/// a request executed via [`execute`] or [`execute_streaming`] failed
+pub fn error_status(err: &anyhow::Error) -> Option<http::StatusCode> {
+    match err.downcast_ref::<tgcs::Error>()? {
+        tgcs::Error::HttpStatus(status) => Some(status.0),
+        tgcs::Error::Api(api_err) => http::StatusCode::from_u16(api_err.code).ok(),
+        _ => None,
+    }
+}
+
+/// Determines if a request failed due to an error that is likely to go away
+/// if the request is retried, eg. a dropped connection or a 503
+pub fn is_transient(err: &anyhow::Error) -> bool {
+    if let Some(status) = error_status(err) {
+        return status.is_server_error()
+            || status == http::StatusCode::TOO_MANY_REQUESTS
+            || status == http::StatusCode::REQUEST_TIMEOUT;
+    }
+
+    err.chain().any(|cause| {
+        cause.downcast_ref::<reqwest::Error>().is_some_and(|err| {
+            err.is_connect() || err.is_timeout() || err.is_request() || err.is_body()
+        }) || cause.downcast_ref::<std::io::Error>().is_some_and(|err| {
+            matches!(
+                err.kind(),
+                std::io::ErrorKind::ConnectionReset
+                    | std::io::ErrorKind::ConnectionAborted
+                    | std::io::ErrorKind::BrokenPipe
+                    | std::io::ErrorKind::TimedOut
+                    | std::io::ErrorKind::UnexpectedEof
+            )
+        })
+    })
+}
+
+/// A number of bytes, which can be specified with an optional binary suffix,
+/// eg. `512`, `256K`, `8M`, `2G`
+#[derive(Copy, Clone, Debug)]
+pub struct ByteSize(pub u64);
+
+impl std::str::FromStr for ByteSize {
+    type Err = clap::Error;
+
+    fn from_str(src: &str) -> Result<Self, Self::Err> {
+        let suffix_pos = src.find(char::is_alphabetic).unwrap_or(src.len());
+
+        let num: u64 = src[..suffix_pos]
+            .parse()
+            .map_err(|err| clap::Error::raw(clap::error::ErrorKind::ValueValidation, err))?;
+
+        let multiplier: u64 = match src[suffix_pos..].trim_end_matches(['b', 'B', 'i']) {
+            "" => 1,
+            "k" | "K" => 1024,
+            "m" | "M" => 1024 * 1024,
+            "g" | "G" => 1024 * 1024 * 1024,
+            "t" | "T" => 1024 * 1024 * 1024 * 1024,
+            s => {
+                return Err(clap::Error::raw(
+                    clap::error::ErrorKind::ValueValidation,
+                    format!("unknown size suffix '{s}'"),
+                ))
+            }
+        };
+
+        num.checked_mul(multiplier).map(Self).ok_or_else(|| {
+            clap::Error::raw(
+                clap::error::ErrorKind::ValueValidation,
+                format!("size '{src}' is too large"),
+            )
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn byte_sizes() {
+        for (src, expected) in [
+            ("0", 0),
+            ("512", 512),
+            ("512B", 512),
+            ("256K", 256 * 1024),
+            ("256k", 256 * 1024),
+            ("8M", 8 * 1024 * 1024),
+            ("8MiB", 8 * 1024 * 1024),
+            ("8mb", 8 * 1024 * 1024),
+            ("2G", 2 * 1024 * 1024 * 1024),
+            ("1T", 1024 * 1024 * 1024 * 1024),
+        ] {
+            assert_eq!(src.parse::<ByteSize>().unwrap().0, expected, "{src}");
+        }
+
+        for invalid in ["", "M", "-1", "1.5M", "8X", "8 M", "16777216T"] {
+            assert!(
+                invalid.parse::<ByteSize>().is_err(),
+                "'{invalid}' was accepted"
+            );
+        }
+    }
+}
@@ -0,0 +1,147 @@
+//! Walking of local directory trees, for commands that operate on every file
+//! beneath a directory
+
+use anyhow::Context as _;
+use std::path::{Path, PathBuf};
+
+/// What to do with symbolic links found while walking a directory
+#[derive(clap::ValueEnum, Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum Symlinks {
+    /// Walk the file or directory the link points to as if it was a regular
+    /// file or directory. Broken links are skipped with a warning.
+    #[default]
+    Follow,
+    /// Ignore the link
+    Skip,
+    /// Fail the walk
+    Fail,
+}
+
+/// Options that control which files are found when walking a directory, shared
+/// by every command that walks directories so that they all behave the same
+#[derive(clap::Args, Clone, Debug, Default)]
+#[group(skip)]
+pub struct Options {
+    /// Skip files and directories whose path relative to the directory being
+    /// walked matches the regular expression, eg. `-x '(^|/)\.git/|\.pdb$'`.
+    /// Directories are matched with a trailing `/`, and nothing beneath an
+    /// excluded directory is walked. Object names relative to the source or
+    /// destination prefix are matched the same way. Can be repeated.
+    #[clap(short = 'x', long = "exclude", value_name = "REGEX")]
+    pub excludes: Vec<regex::Regex>,
+    /// What to do with symbolic links
+    #[clap(long, value_enum, default_value_t)]
+    pub symlinks: Symlinks,
+    /// Print every file, directory, and object that is skipped due to
+    /// `--exclude` or `--symlinks skip`
+    #[clap(short = 'v', long)]
+    pub verbose: bool,
+}
+
+impl Options {
+    /// Checks if the relative path is excluded, reporting it if it is
+    pub fn is_excluded(&self, relative: &str) -> bool {
+        let excluded = self.excludes.iter().any(|re| re.is_match(relative));
+
+        if excluded && self.verbose {
+            eprintln!("Skipping excluded path: {relative}");
+        }
+
+        excluded
+    }
+}
+
+/// A file found while walking a directory tree
+pub struct LocalFile {
+    /// The path of the file
+    pub path: PathBuf,
+    /// The path of the file relative to the root of the walk, always using `/`
+    /// as the separator so that it can be used as part of an object name
+    pub relative: String,
+    /// The length of the file in bytes
+    pub len: u64,
+    /// The modification time of the file in unix seconds
+    pub mtime: Option<i64>,
+}
+
+/// Recursively walks a directory, returning every file beneath it that isn't
+/// excluded, sorted by their relative path. When symlinks are followed,
+/// directories are only ever visited once so that symlink cycles don't cause
+/// infinite recursion.
+pub fn walk(root: &Path, opts: &Options) -> anyhow::Result<Vec<LocalFile>> {
+    let mut files = Vec::new();
+    let mut visited = std::collections::HashSet::new();
+    let mut dirs = vec![(root.to_owned(), String::new())];
+
+    while let Some((dir, relative)) = dirs.pop() {
+        if !visited.insert(
+            dir.canonicalize()
+                .with_context(|| format!("failed to resolve '{}'", dir.display()))?,
+        ) {
+            continue;
+        }
+
+        for entry in std::fs::read_dir(&dir)
+            .with_context(|| format!("failed to read '{}'", dir.display()))?
+        {
+            let entry = entry?;
+            let path = entry.path();
+
+            let name = entry.file_name();
+            let name = name
+                .to_str()
+                .with_context(|| format!("'{}' is not a valid UTF-8 path", path.display()))?;
+            let relative = format!("{relative}{name}");
+
+            let is_symlink = entry
+                .file_type()
+                .with_context(|| format!("failed to read metadata for '{}'", path.display()))?
+                .is_symlink();
+
+            if is_symlink {
+                match opts.symlinks {
+                    Symlinks::Follow => {}
+                    Symlinks::Skip => {
+                        if opts.verbose {
+                            eprintln!("Skipping symlink: {relative}");
+                        }
+                        continue;
+                    }
+                    Symlinks::Fail => {
+                        anyhow::bail!("'{}' is a symbolic link", path.display());
+                    }
+                }
+            }
+
+            // Follow symlinks to whatever they point to
+            let md = match std::fs::metadata(&path) {
+                Ok(md) => md,
+                Err(err) if is_symlink && err.kind() == std::io::ErrorKind::NotFound => {
+                    eprintln!("Skipping broken symlink: {relative}");
+                    continue;
+                }
+                Err(err) => {
+                    return Err(anyhow::Error::new(err)
+                        .context(format!("failed to read metadata for '{}'", path.display())))
+                }
+            };
+
+            if md.is_dir() {
+                let relative = format!("{relative}/");
+                if !opts.is_excluded(&relative) {
+                    dirs.push((path, relative));
+                }
+            } else if md.is_file() && !opts.is_excluded(&relative) {
+                files.push(LocalFile {
+                    path,
+                    relative,
+                    len: md.len(),
+                    mtime: crate::util::unix_mtime(&md),
+                });
+            }
+        }
+    }
+
+    files.sort_by(|a, b| a.relative.cmp(&b.relative));
+    Ok(files)
+}
@@ -0,0 +1,195 @@
+//! Expansion of wildcards in the object names of gs:// urls, eg.
+//! `gs://bucket/logs/**/*.json`. The following wildcards are supported.
+//!
+//! * `*` - Matches zero or more characters, except for `/`
+//! * `**` - Matches zero or more characters, including `/`
+//! * `?` - Matches exactly one character, except for `/`
+//! * `[abc]` - Matches exactly one of the characters in the brackets
+//!
+//! The matching itself is done by GCS via the `matchGlob` parameter of
+//! [list](https://cloud.google.com/storage/docs/json_api/v1/objects/list#list-objects-and-prefixes-using-glob),
+//! so that only the matching objects are returned, using the portion of the
+//! name before the first wildcard as the list prefix.
+
+use crate::util;
+use anyhow::Context as _;
+use tame_gcs::{self as tgcs, objects::Metadata};
+
+const WILDCARDS: [char; 3] = ['*', '?', '['];
+
+/// Determines if the string contains any wildcards
+#[inline]
+pub fn has_wildcard(s: &str) -> bool {
+    s.contains(WILDCARDS)
+}
+
+/// A gs:// url whose object name may contain wildcards. Unlike [`util::GsUrl`]
+/// the object name is not validated, as wildcards are not valid characters in
+/// an object name.
+pub struct GsPattern {
+    bucket_name: tgcs::BucketName<'static>,
+    pattern: String,
+}
+
+impl GsPattern {
+    /// Parses a `gs://<bucket_name>/<pattern>` url
+    pub fn parse(url: &str) -> anyhow::Result<Self> {
+        let url = url
+            .strip_prefix("gs://")
+            .with_context(|| format!("'{url}' is not a gs:// url"))?;
+
+        let (bucket_name, pattern) = url.split_once('/').unwrap_or((url, ""));
+        anyhow::ensure!(!bucket_name.is_empty(), "no bucket specified");
+        anyhow::ensure!(
+            !has_wildcard(bucket_name),
+            "wildcards are not supported in bucket names"
+        );
+
+        Ok(Self {
+            bucket_name: tgcs::BucketName::try_from(bucket_name.to_owned())?,
+            pattern: pattern.to_owned(),
+        })
+    }
+
+    #[inline]
+    pub fn bucket(&self) -> &tgcs::BucketName<'_> {
+        &self.bucket_name
+    }
+
+    /// The object name, which may contain wildcards
+    #[inline]
+    pub fn pattern(&self) -> &str {
+        &self.pattern
+    }
+
+    #[inline]
+    pub fn has_wildcard(&self) -> bool {
+        has_wildcard(&self.pattern)
+    }
+
+    /// The portion of the object name before the first wildcard
+    pub fn literal_prefix(&self) -> &str {
+        let end = self.pattern.find(WILDCARDS).unwrap_or(self.pattern.len());
+        &self.pattern[..end]
+    }
+
+    /// Lists the metadata of every object matching the pattern, which must
+    /// contain a wildcard. If `item_fields` is specified only those fields are
+    /// retrieved for each object.
+    pub async fn matches(
+        &self,
+        ctx: &util::RequestContext,
+        item_fields: Option<&str>,
+    ) -> anyhow::Result<Vec<Metadata>> {
+        let objects = util::list_objects(
+            ctx,
+            &self.bucket_name,
+            self.literal_prefix(),
+            Some(&self.pattern),
+            item_fields,
+        )
+        .await?;
+
+        anyhow::ensure!(!objects.is_empty(), "no urls matched '{self}'");
+        Ok(objects)
+    }
+
+    /// Expands the pattern into the urls of every object it matches. A pattern
+    /// without any wildcards is returned as is, without checking if the
+    /// object actually exists.
+    pub async fn expand(&self, ctx: &util::RequestContext) -> anyhow::Result<Vec<util::GsUrl>> {
+        if !self.has_wildcard() {
+            return Ok(vec![self.to_url()?]);
+        }
+
+        self.matches(ctx, Some("name"))
+            .await?
+            .into_iter()
+            .map(|md| {
+                Ok(util::GsUrl::new(
+                    tgcs::BucketName::try_from(self.bucket_name.to_string())?,
+                    Some(tgcs::ObjectName::try_from(
+                        md.name.context("object is missing a name")?,
+                    )?),
+                ))
+            })
+            .collect()
+    }
+
+    /// Converts a pattern without wildcards into a regular url, which only
+    /// refers to the bucket itself if the pattern is empty
+    pub fn to_url(&self) -> anyhow::Result<util::GsUrl> {
+        let obj_name = if self.pattern.is_empty() {
+            None
+        } else {
+            Some(
+                tgcs::ObjectName::try_from(self.pattern.clone())
+                    .with_context(|| format!("'{self}' is not a valid object name"))?,
+            )
+        };
+
+        Ok(util::GsUrl::new(
+            tgcs::BucketName::try_from(self.bucket_name.to_string())?,
+            obj_name,
+        ))
+    }
+}
+
+impl std::fmt::Display for GsPattern {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "gs://{}/{}", self.bucket_name, self.pattern)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parses_patterns() {
+        for url in ["gs://bkt", "gs://bkt/"] {
+            let pattern = GsPattern::parse(url).unwrap();
+            assert_eq!(pattern.bucket().to_string(), "bkt");
+            assert_eq!(pattern.pattern(), "");
+            assert_eq!(pattern.literal_prefix(), "");
+            assert!(!pattern.has_wildcard());
+        }
+
+        let pattern = GsPattern::parse("gs://bkt/a/**/*.json").unwrap();
+        assert_eq!(pattern.pattern(), "a/**/*.json");
+        assert_eq!(pattern.literal_prefix(), "a/");
+        assert!(pattern.has_wildcard());
+
+        let pattern = GsPattern::parse("gs://bkt/x?y").unwrap();
+        assert_eq!(pattern.literal_prefix(), "x");
+        assert!(pattern.has_wildcard());
+
+        let pattern = GsPattern::parse("gs://bkt/a/b.txt").unwrap();
+        assert_eq!(pattern.literal_prefix(), "a/b.txt");
+        assert!(!pattern.has_wildcard());
+
+        for url in [
+            "gs://bkt*/a",
+            "gs://bkt?",
+            "gs://[bkt]/a",
+            "gs:///a",
+            "bkt/a",
+        ] {
+            assert!(GsPattern::parse(url).is_err(), "'{url}' was accepted");
+        }
+    }
+
+    #[test]
+    fn invalid_object_names_are_rejected() {
+        assert!(GsPattern::parse("gs://bkt")
+            .unwrap()
+            .to_url()
+            .unwrap()
+            .object()
+            .is_none());
+        assert!(GsPattern::parse("gs://bkt/build#42/")
+            .unwrap()
+            .to_url()
+            .is_err());
+    }
+}